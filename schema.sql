@@ -1,3 +1,5 @@
+-- Makes a new database. Databases made with an older schema.sql are brought up to date with upgrade.sql instead
+
 CREATE TABLE cryptodata (
   id VARCHAR(256) NOT NULL,
   asOf TIMESTAMP NOT NULL DEFAULT NOW(), -- the datetime which this quote was pulled from coingecko
@@ -13,18 +15,44 @@ CREATE TABLE cryptodata (
 
 CREATE INDEX IDX_cryptoname ON cryptodata(name); -- name lookups are now fast :)
 CREATE INDEX IDX_cryptoasof ON cryptodata(asOf); 
+CREATE INDEX IDX_cryptodata_latest ON cryptodata(id, asOf DESC); -- newest price of a coin, e.g. for each order the matcher checks
 
 -- Wallet for each user for each server, each user can participate in multiple servers.
 CREATE TABLE wallet (
-  userId VARCHAR(256) PRIMARY KEY,
-  walletBalance NUMERIC(25,4)
+  userId VARCHAR(256) NOT NULL,
+  serverId VARCHAR(256) NOT NULL DEFAULT '', -- '' is the user's global wallet, used when no server is given
+  walletBalance NUMERIC(25,4),
+  PRIMARY KEY (userId, serverId)
 );
 
+-- API keys are handed out as '<id>.<secret>' by create_api_key. Only a salted hash of the secret is kept
 CREATE TABLE apikeys (
   id SERIAL PRIMARY KEY,
-  key_str VARCHAR(512),
-  description VARCHAR(1024)
+  salt BYTEA NOT NULL,
+  keyHash BYTEA NOT NULL, -- hash_api_key(salt, secret)
+  scopes VARCHAR(32)[] NOT NULL DEFAULT '{read}', -- admin keys can call every endpoint, the others only those needing one of their scopes
+  description VARCHAR(1024),
+  createdAt TIMESTAMP NOT NULL DEFAULT NOW(),
+  lastUsedAt TIMESTAMP, -- the API writes this in batches, so it can lag by up to CB_API_KEY_REFRESH_INTERVAL
+  expiresAt TIMESTAMP, -- set by rotate_api_key, the key keeps working until then
+  revokedAt TIMESTAMP,
+  readLimit INT CHECK (readLimit > 0), -- GET requests allowed per CB_RATE_LIMIT_WINDOW, NULL for CB_RATE_LIMIT_KEY_READ
+  tradeLimit INT CHECK (tradeLimit > 0), -- other requests allowed per window, NULL for CB_RATE_LIMIT_KEY_TRADE
+  signingSecret VARCHAR(64), -- when set, requests with the key must be HMAC-SHA256 signed with it. Kept as is, unlike the key's secret
+  legacy BOOLEAN NOT NULL DEFAULT false, -- carried over from key_str by upgrade.sql, so sent whole rather than as '<id>.<secret>'
+  CHECK (scopes <@ ARRAY['read','trade','admin','leaderboard-write']::VARCHAR(32)[])
+);
+
+-- every change made to apikeys through create_api_key, rotate_api_key, revoke_api_key, set_api_key_limits and set_api_key_signing
+CREATE TABLE apikeyaudit (
+  auditId SERIAL PRIMARY KEY,
+  keyId INT NOT NULL REFERENCES apikeys(id),
+  action VARCHAR(16) NOT NULL, -- 'created', 'rotated', 'revoked', 'limits' or 'signing'
+  actorKeyId INT REFERENCES apikeys(id), -- admin key that made the change, NULL when made from SQL or with auth off
+  detail VARCHAR(1024),
+  ts TIMESTAMP NOT NULL DEFAULT NOW()
 );
+CREATE INDEX IDX_apikeyaudit_keyId ON apikeyaudit(keyId);
 
 CREATE TABLE serverpatrons (
   serverId VARCHAR(256),
@@ -34,25 +62,135 @@ CREATE TABLE serverpatrons (
 );
 CREATE INDEX IDX_serverpatrons_serverId on serverpatrons(serverId);
 
+-- one row per claimed daily reward, at most one claim per wallet per UTC day
+CREATE TABLE dailyrewards (
+  userId VARCHAR(256) NOT NULL,
+  serverId VARCHAR(256) NOT NULL DEFAULT '',
+  claimDate DATE NOT NULL, -- UTC day the reward was claimed for
+  streak INT NOT NULL, -- number of consecutive days claimed, including this one
+  amount NUMERIC(25,4) NOT NULL,
+  claimedAt TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (userId, serverId, claimDate)
+);
+
+-- every wallet's net worth, written by snapshot_networth once per snapshot interval
+CREATE TABLE networthsnapshots (
+  userId VARCHAR(256) NOT NULL,
+  serverId VARCHAR(256) NOT NULL DEFAULT '',
+  snapshotAt TIMESTAMP NOT NULL, -- start of the interval the snapshot belongs to, so reruns within it are no-ops
+  cash NUMERIC(25,4) NOT NULL, -- walletBalance plus the cash open buy orders reserved
+  portfolioValue NUMERIC(50,10) NOT NULL,
+  netWorth NUMERIC(50,10) NOT NULL,
+  takenAt TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (userId, serverId, snapshotAt)
+);
+
 -- transactions belong to a serverwallet (which is unique to a (userId,serverId) combo)
 CREATE TABLE transactions (
   transactionId SERIAL,
   transactionTime TIMESTAMP NOT NULL DEFAULT NOW(),
   userId VARCHAR(256),--Looks like SERIAL is INT in pg VARCHAR(256),
+  serverId VARCHAR(256) NOT NULL DEFAULT '', -- wallet the transaction was made from, '' for the global wallet
   cryptoId VARCHAR(256),
   cost NUMERIC(25,4) NOT NULL, -- negative indicates a sell positive indicates a buy
   buySellIndicator CHAR(1) NOT NULL, -- makes life a little bit easier so we dont have to compare cost to 0 to get Buy or Sell
   qty NUMERIC(25,8) NOT NULL,-- amount of crypto purchased / sold positive indicates buy and negative indicates sell
+  price NUMERIC(50,10), -- price per coin the trade filled at. cost is rounded to 4 places, so cost / qty is only close to it
+  fee NUMERIC(25,4) NOT NULL DEFAULT 0, -- commission paid on top of cost for a buy, or taken out of the proceeds of a sell
   PRIMARY KEY (transactionId),
   CONSTRAINT CHK_buySellIndicator CHECK(buySellIndicator in ('B','S')),
   constraint CHK_cost CHECK( (cost > 0 and qty > 0 and buySellIndicator = 'B') or (cost < 0 and qty < 0 and buySellIndicator = 'S'))
 );
 
-create index IDX_transactions on transactions (userId,cryptoId);
+create index IDX_transactions on transactions (userId,serverId,cryptoId);
+
+-- resting limit orders, filled by the order matcher when a newer cryptodata row crosses limitPrice
+CREATE TABLE orders (
+  orderId SERIAL PRIMARY KEY,
+  userId VARCHAR(256) NOT NULL,
+  serverId VARCHAR(256) NOT NULL DEFAULT '',
+  cryptoId VARCHAR(256) NOT NULL,
+  side CHAR(1) NOT NULL, -- same B/S convention as transactions.buySellIndicator
+  qty NUMERIC(25,8) NOT NULL,
+  limitPrice NUMERIC(50,10) NOT NULL,
+  reserved NUMERIC(25,4) NOT NULL DEFAULT 0, -- cash taken from the wallet when a buy order is placed
+  status VARCHAR(16) NOT NULL DEFAULT 'open',
+  createdAt TIMESTAMP NOT NULL DEFAULT NOW(),
+  expiresAt TIMESTAMP,
+  closedAt TIMESTAMP, -- when the order was filled, cancelled or expired
+  fillPrice NUMERIC(50,10),
+  transactionId INT REFERENCES transactions(transactionId),
+  CONSTRAINT CHK_orders_side CHECK(side in ('B','S')),
+  CONSTRAINT CHK_orders_status CHECK(status in ('open','filled','cancelled','expired')),
+  CONSTRAINT CHK_orders_qty CHECK(qty > 0 and limitPrice > 0)
+);
+
+create index IDX_orders_user on orders (userId,serverId,status);
+create index IDX_orders_open on orders (cryptoId) where status = 'open';
+
+-- stop-loss / take-profit triggers on a held position, turned into a market sell once the price crosses triggerPrice
+CREATE TABLE pricetriggers (
+  triggerId SERIAL PRIMARY KEY,
+  userId VARCHAR(256) NOT NULL,
+  serverId VARCHAR(256) NOT NULL DEFAULT '',
+  cryptoId VARCHAR(256) NOT NULL,
+  kind VARCHAR(16) NOT NULL, -- stop_loss sells when price <= triggerPrice, take_profit when price >= triggerPrice
+  triggerPrice NUMERIC(50,10) NOT NULL,
+  qty NUMERIC(25,8), -- NULL sells the whole position
+  status VARCHAR(16) NOT NULL DEFAULT 'active',
+  createdAt TIMESTAMP NOT NULL DEFAULT NOW(),
+  closedAt TIMESTAMP,
+  firedPrice NUMERIC(50,10),
+  transactionId INT REFERENCES transactions(transactionId), -- the sell made when the trigger fired
+  closeReason VARCHAR(512),
+  CONSTRAINT CHK_pricetriggers_kind CHECK(kind in ('stop_loss','take_profit')),
+  CONSTRAINT CHK_pricetriggers_status CHECK(status in ('active','fired','cancelled')),
+  CONSTRAINT CHK_pricetriggers_qty CHECK((qty is null or qty > 0) and triggerPrice > 0)
+);
+
+create index IDX_pricetriggers_user on pricetriggers (userId,serverId,status);
+create index IDX_pricetriggers_active on pricetriggers (cryptoId) where status = 'active';
+
+-- a server's trading fees. Columns left NULL use the CB_FEE_* defaults
+CREATE TABLE serverfees (
+  serverId VARCHAR(256) PRIMARY KEY,
+  commissionPct NUMERIC(10,6), -- percent of the value of a trade
+  minFee NUMERIC(25,4), -- smallest commission charged on a trade
+  spreadPct NUMERIC(10,6), -- percent between the bid and ask price, centred on the cryptodata price
+  CONSTRAINT CHK_serverfees CHECK(commissionPct >= 0 and commissionPct < 100 and minFee >= 0 and spreadPct >= 0 and spreadPct < 200)
+);
+
+-- prices handed out by POST /quotes. A quote can be executed once, at its price, until it expires
+CREATE TABLE quotes (
+  quoteId SERIAL PRIMARY KEY,
+  userId VARCHAR(256) NOT NULL,
+  serverId VARCHAR(256) NOT NULL DEFAULT '',
+  cryptoId VARCHAR(256) NOT NULL,
+  side CHAR(1) NOT NULL, -- same B/S convention as transactions.buySellIndicator
+  qty NUMERIC(25,8) NOT NULL,
+  price NUMERIC(50,10) NOT NULL,
+  createdAt TIMESTAMP NOT NULL DEFAULT NOW(),
+  expiresAt TIMESTAMP NOT NULL,
+  usedAt TIMESTAMP,
+  transactionId INT REFERENCES transactions(transactionId),
+  CONSTRAINT CHK_quotes_side CHECK(side in ('B','S')),
+  CONSTRAINT CHK_quotes_qty CHECK(qty > 0 and price > 0)
+);
 
 
 -- portfolio, and leaderboards should be views since they inherently change all the time and can be calculated from the data above
 
+-- apikeys without the hashes and signing secrets, and whether each key still works
+CREATE VIEW vApiKeys AS
+SELECT id, description, scopes, readLimit, tradeLimit, signingSecret IS NOT NULL AS signed, legacy, createdAt, lastUsedAt, expiresAt, revokedAt,
+  CASE
+    WHEN revokedAt IS NOT NULL THEN 'revoked'
+    WHEN expiresAt <= NOW() THEN 'expired'
+    WHEN expiresAt IS NOT NULL THEN 'expiring' -- rotated, but still in its grace period
+    ELSE 'active'
+  END AS status
+FROM apikeys;
+
 CREATE VIEW vPortfolio AS
 WITH cteLatestPrices AS (
   SELECT id as cryptoId, symbol, name, price as latestPrice FROM (SELECT id, price, symbol, name, ROW_NUMBER() OVER(partition by id order by asOf DESC) as rn
@@ -60,15 +198,18 @@ WITH cteLatestPrices AS (
 ),
 ctePositions AS (
   SELECT t.userId, 
+  t.serverId,
   t.cryptoId, 
   SUM(t.qty) as qty,
   MAX(t.transactionTime) as lastTransactionTs
   FROM transactions t 
   GROUP BY 
     t.userId,
+    t.serverId,
     t.cryptoId
 )
 SELECT userId,
+serverId,
 p.cryptoId,
 lp.symbol,
 lp.name,
@@ -82,72 +223,565 @@ WHERE qty > 0;
 
 create view vNetworth AS
 with ctePortfolioValue as (
-	select userId, SUM(currentValue) as portfolioValue from vportfolio v group by userid
+	select userId, serverId, SUM(currentValue) as portfolioValue from vportfolio v group by userid, serverid
+),
+-- cash open buy orders took out of the wallet is still the user's
+cteReserved as (
+	select userId, serverId, SUM(reserved) as reserved from orders where status = 'open' group by userId, serverId
 )
-select w.userid, coalesce(pv.portfolioValue,0.0)+w.walletbalance as netWorth 
-from wallet w left join ctePortfolioValue pv on w.userid = pv.userId
+select w.userid, w.serverid, w.walletBalance, coalesce(r.reserved,0) as reserved, coalesce(pv.portfolioValue,0.0) as portfolioValue,
+  coalesce(pv.portfolioValue,0.0)+w.walletbalance+coalesce(r.reserved,0) as netWorth 
+from wallet w left join ctePortfolioValue pv on w.userid = pv.userId and w.serverid = pv.serverId
+left join cteReserved r on w.userid = r.userId and w.serverid = r.serverId;
+
 
 
+-- the fees trades from l_serverId's wallets pay: its serverfees row, with the defaults passed in for anything it leaves NULL
+create function trade_fees(l_serverId VARCHAR(256), l_commissionPct NUMERIC, l_minFee NUMERIC, l_spreadPct NUMERIC) returns serverfees AS
+ $BODY$
+select l_serverId, coalesce(f.commissionPct, l_commissionPct), coalesce(f.minFee, l_minFee), coalesce(f.spreadPct, l_spreadPct)
+from (select 1) d left join serverfees f on f.serverId = l_serverId;
+$BODY$
+ LANGUAGE sql STABLE;
+
+-- commission on a trade worth l_notional. NULL fees charge nothing
+create function trade_fee(l_notional NUMERIC, l_fees serverfees) returns NUMERIC AS
+ $BODY$
+select round(greatest(l_notional * coalesce(l_fees.commissionPct, 0) / 100, coalesce(l_fees.minFee, 0)), 4);
+$BODY$
+ LANGUAGE sql IMMUTABLE;
 
-create function buy_currency(qty numeric(50,10), l_cryptoId VARCHAR(32), l_userId VARCHAR(256)) returns void AS
+-- price a market buy ('B') or sell ('S') fills at: l_mid moved half the spread against the trader
+create function trade_price(l_mid NUMERIC, l_side CHAR(1), l_fees serverfees) returns NUMERIC AS
  $BODY$
+select round(l_mid * (1 + (case when l_side = 'B' then 1 else -1 end) * coalesce(l_fees.spreadPct, 0) / 200), 10);
+$BODY$
+ LANGUAGE sql IMMUTABLE;
+
+-- l_maxQuoteAge rejects the trade when the newest price is older than that. NULL trades at any price.
+-- l_price trades at that price instead of the newest one, for executing a quote. Returns the new transactionId
+-- l_amount buys as much as that amount of cash pays for, commission included, instead of qty, rounded down to whole
+-- 0.00000001s of the coin. l_fees (from trade_fees) sets the spread on the newest price and the commission
+create function buy_currency(qty numeric(50,10), l_cryptoId VARCHAR(32), l_userId VARCHAR(256), l_serverId VARCHAR(256) DEFAULT '', l_maxQuoteAge INTERVAL DEFAULT NULL, l_price NUMERIC(50,10) DEFAULT NULL, l_amount NUMERIC(50,10) DEFAULT NULL, l_fees serverfees DEFAULT NULL) returns int AS
+ $BODY$
+declare priceAsOf TIMESTAMP := NULL;
+declare newTransactionId int;
 declare wbal numeric(50,10) := 0.0;
 declare currentPrice NUMERIC(50,10) := NULL;
+declare commission NUMERIC := coalesce(l_fees.commissionPct, 0) / 100;
+declare minFee NUMERIC := coalesce(l_fees.minFee, 0);
+declare fee NUMERIC(25,4);
 BEGIN
-if (qty <= 0) then 
+if (l_amount is not null) then
+  if (l_amount <= 0) then
+   raise exception 'Amount must be a positive decimal!';
+  end if;
+elsif (qty is null or qty <= 0) then 
  raise exception 'Qty must be a positive decimal!';
+elsif (qty <> trunc(qty, 8)) then
+ raise exception 'Qty can have at most 8 decimal places!';
 end if;
--- fetch current price
-select price into currentPrice from cryptodata c where c.id = l_cryptoId order by asOf desc limit 1;
-if (currentPrice is null or currentPrice <= 0.0) then 
-	  raise exception 'Could not find a nonzero price for %',l_cryptoId;
+if (l_price is not null) then
+  currentPrice := l_price;
+else
+  -- fetch current price
+  select price, asOf into currentPrice, priceAsOf from cryptodata c where c.id = l_cryptoId order by asOf desc limit 1;
+  if (currentPrice is null or currentPrice <= 0.0) then 
+  	  raise exception 'Could not find a nonzero price for %',l_cryptoId;
+  end if;
+  if (l_maxQuoteAge is not null and priceAsOf < NOW()::TIMESTAMP - l_maxQuoteAge) then
+  	  raise exception 'Stale price for %: the newest quote is from % UTC', l_cryptoId, to_char(priceAsOf, 'YYYY-MM-DD HH24:MI:SS');
+  end if;
+  currentPrice := trade_price(currentPrice, 'B', l_fees);
+end if;
+-- the ledger keeps 8 decimal places of qty, so round down to never spend more than asked for. The commission comes out
+-- of l_amount too: a percentage of the value bought unless that is under the minimum, which is then taken off first
+if (l_amount is not null) then
+  qty := trunc(l_amount / (currentPrice * (1 + commission)), 8);
+  if (qty * currentPrice * commission < minFee) then
+    qty := trunc((l_amount - minFee) / currentPrice, 8);
+  end if;
+  if (qty <= 0) then
+    raise exception 'Amount % is worth less than 0.00000001 %', l_amount, l_cryptoId;
+  end if;
 end if;
 -- explicitly lock the the wallet table in row exclusive mode
 lock table wallet in row exclusive mode;
 select w.walletbalance into wbal from wallet w
-where w.userId = l_userId for update;
+where w.userId = l_userId and w.serverId = l_serverId for update;
+fee := trade_fee(qty*currentPrice, l_fees);
 -- make sure they have enough money
-if (wbal is null or (wbal - qty*currentPrice) < 0.0) then
+if (wbal is null or (wbal - qty*currentPrice - fee) < 0.0) then
      raise exception 'Insufficient funds';
 end if;
 -- update wallet balance
-update wallet set walletbalance = (wbal - qty*currentPrice);
+update wallet set walletbalance = (wbal - qty*currentPrice - fee) where userId = l_userId and serverId = l_serverId;
 -- create the transaction
-insert into transactions (userId,cryptoid,cost,buysellindicator,qty) 
-values (l_userId,l_cryptoId,qty*currentPrice,'B',qty);
+insert into transactions (userId,serverId,cryptoid,cost,buysellindicator,qty,price,fee) 
+values (l_userId,l_serverId,l_cryptoId,qty*currentPrice,'B',qty,currentPrice,fee)
+returning transactionId into newTransactionId;
+return newTransactionId;
 end $BODY$
  LANGUAGE 'plpgsql' 
 COST 100;
 
 -- create sell_currency fn
-create function sell_currency(qty numeric(50,10), l_cryptoId VARCHAR(32), l_userId VARCHAR(256)) returns void AS
+-- l_maxQuoteAge rejects the trade when the newest price is older than that. NULL trades at any price.
+-- l_price trades at that price instead of the newest one, for executing a quote. Returns the new transactionId
+-- l_amount sells as much as raises that amount of cash after commission instead of qty, rounded down to whole
+-- 0.00000001s of the coin. A NULL qty without l_amount sells the whole position, less anything held for open sell orders.
+-- l_fees (from trade_fees) sets the spread on the newest price and the commission, which never takes more than the sale raises
+create function sell_currency(qty numeric(50,10), l_cryptoId VARCHAR(32), l_userId VARCHAR(256), l_serverId VARCHAR(256) DEFAULT '', l_maxQuoteAge INTERVAL DEFAULT NULL, l_price NUMERIC(50,10) DEFAULT NULL, l_amount NUMERIC(50,10) DEFAULT NULL, l_fees serverfees DEFAULT NULL) returns int AS
  $BODY$
+declare priceAsOf TIMESTAMP := NULL;
+declare newTransactionId int;
 declare ownedAmnt numeric(50,10) := 0.0;
 declare currentPrice NUMERIC(50,10) := NULL;
+declare commission NUMERIC := coalesce(l_fees.commissionPct, 0) / 100;
+declare minFee NUMERIC := coalesce(l_fees.minFee, 0);
+declare fee NUMERIC(25,4);
 BEGIN
-if (qty <= 0) then 
+if (l_amount is not null) then
+  if (l_amount <= 0) then
+   raise exception 'Amount must be a positive decimal!';
+  end if;
+elsif (qty <= 0) then 
  raise exception 'Qty must be a positive decimal!';
+elsif (qty <> trunc(qty, 8)) then
+ raise exception 'Qty can have at most 8 decimal places!';
 end if;
--- fetch current price
-select price into currentPrice from cryptodata c where c.id = l_cryptoId order by asOf desc limit 1;
-if (currentPrice is null or currentPrice <= 0.0) then 
-	  raise exception 'Could not find a nonzero price for %',l_cryptoId;
+if (l_price is not null) then
+  currentPrice := l_price;
+else
+  -- fetch current price
+  select price, asOf into currentPrice, priceAsOf from cryptodata c where c.id = l_cryptoId order by asOf desc limit 1;
+  if (currentPrice is null or currentPrice <= 0.0) then 
+  	  raise exception 'Could not find a nonzero price for %',l_cryptoId;
+  end if;
+  if (l_maxQuoteAge is not null and priceAsOf < NOW()::TIMESTAMP - l_maxQuoteAge) then
+  	  raise exception 'Stale price for %: the newest quote is from % UTC', l_cryptoId, to_char(priceAsOf, 'YYYY-MM-DD HH24:MI:SS');
+  end if;
+  currentPrice := trade_price(currentPrice, 'S', l_fees);
+end if;
+-- the ledger keeps 8 decimal places of qty, so round down to never raise more than asked for. l_amount is what is left
+-- after commission: a percentage of the value sold unless that is under the minimum, which is then sold on top
+if (l_amount is not null) then
+  qty := trunc(l_amount / (currentPrice * (1 - commission)), 8);
+  if (qty * currentPrice * commission < minFee) then
+    qty := trunc((l_amount + minFee) / currentPrice, 8);
+  end if;
+  if (qty <= 0) then
+    raise exception 'Amount % is worth less than 0.00000001 %', l_amount, l_cryptoId;
+  end if;
 end if;
 -- explicitly lock the the transactions table to prevent overselling
 lock table transactions;
 
-select SUM(t.qty) into ownedAmnt from transactions t where t.userid = l_userId and t.cryptoid = l_cryptoId;
+select SUM(t.qty) into ownedAmnt from transactions t where t.userid = l_userId and t.serverid = l_serverId and t.cryptoid = l_cryptoId;
+-- coins promised to open sell orders can't be sold twice
+select ownedAmnt - coalesce(SUM(o.qty), 0) into ownedAmnt from orders o
+where o.userId = l_userId and o.serverId = l_serverId and o.cryptoId = l_cryptoId and o.side = 'S' and o.status = 'open';
+if (qty is null) then
+  qty := ownedAmnt;
+end if;
 -- make sure they have enough coin
-if (ownedAmnt is null or ownedAmnt < qty) then
+if (ownedAmnt is null or ownedAmnt <= 0 or ownedAmnt < qty) then
      raise exception 'Insufficient funds';
 end if;
+fee := least(trade_fee(qty*currentPrice, l_fees), qty*currentPrice);
 -- update wallet balance
-update wallet set walletbalance = (walletbalance + qty*currentPrice);
+update wallet set walletbalance = (walletbalance + qty*currentPrice - fee) where userId = l_userId and serverId = l_serverId;
 -- create the transaction
-insert into transactions (userId,cryptoid,cost,buysellindicator,qty) 
-values (l_userId,l_cryptoId,-1*qty*currentPrice,'S',-1*qty);
+insert into transactions (userId,serverId,cryptoid,cost,buysellindicator,qty,price,fee) 
+values (l_userId,l_serverId,l_cryptoId,-1*qty*currentPrice,'S',-1*qty,currentPrice,fee)
+returning transactionId into newTransactionId;
+return newTransactionId;
 end $BODY$
  LANGUAGE 'plpgsql' 
 COST 100;
 
--- I will finish leaderboards later
\ No newline at end of file
+-- places a limit order, reserving cash for a buy (with the commission it pays at the limit price) or coins for a sell.
+-- Returns the new orderId
+create function place_limit_order(l_userId VARCHAR(256), l_serverId VARCHAR(256), l_cryptoId VARCHAR(256), l_side CHAR(1), l_qty NUMERIC(25,8), l_limitPrice NUMERIC(50,10), l_expiresAt TIMESTAMP, l_fees serverfees DEFAULT NULL) returns int AS
+ $BODY$
+declare wbal numeric(50,10) := 0.0;
+declare reserve numeric(50,10) := l_qty*l_limitPrice + trade_fee(l_qty*l_limitPrice, l_fees);
+declare ownedAmnt numeric(50,10) := 0.0;
+declare newOrderId int;
+BEGIN
+if (l_qty <= 0) then 
+ raise exception 'Qty must be a positive decimal!';
+end if;
+if (l_limitPrice <= 0) then 
+ raise exception 'Limit price must be a positive decimal!';
+end if;
+if (l_expiresAt is not null and l_expiresAt <= NOW()) then
+ raise exception 'Expiry must be in the future!';
+end if;
+if not exists (select 1 from cryptodata c where c.id = l_cryptoId) then
+	  raise exception 'Could not find a nonzero price for %',l_cryptoId;
+end if;
+if (l_side = 'B') then
+  lock table wallet in row exclusive mode;
+  select w.walletbalance into wbal from wallet w
+  where w.userId = l_userId and w.serverId = l_serverId for update;
+  if (wbal is null or (wbal - reserve) < 0.0) then
+       raise exception 'Insufficient funds';
+  end if;
+  update wallet set walletbalance = (wbal - reserve) where userId = l_userId and serverId = l_serverId;
+  insert into orders (userId,serverId,cryptoId,side,qty,limitPrice,reserved,expiresAt)
+  values (l_userId,l_serverId,l_cryptoId,'B',l_qty,l_limitPrice,reserve,l_expiresAt)
+  returning orderId into newOrderId;
+else
+  -- same lock sell_currency takes, so a sale and an order can't both claim the same coins
+  lock table transactions;
+  select SUM(t.qty) into ownedAmnt from transactions t where t.userid = l_userId and t.serverid = l_serverId and t.cryptoid = l_cryptoId;
+  select ownedAmnt - coalesce(SUM(o.qty), 0) into ownedAmnt from orders o
+  where o.userId = l_userId and o.serverId = l_serverId and o.cryptoId = l_cryptoId and o.side = 'S' and o.status = 'open';
+  if (ownedAmnt is null or ownedAmnt < l_qty) then
+       raise exception 'Insufficient funds';
+  end if;
+  insert into orders (userId,serverId,cryptoId,side,qty,limitPrice,expiresAt)
+  values (l_userId,l_serverId,l_cryptoId,'S',l_qty,l_limitPrice,l_expiresAt)
+  returning orderId into newOrderId;
+end if;
+return newOrderId;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- closes an open order as 'cancelled' or 'expired', returning reserved cash to the wallet. Returns false if the order wasn't open
+create function cancel_limit_order(l_orderId int, l_status VARCHAR(16)) returns boolean AS
+ $BODY$
+declare o orders%ROWTYPE;
+BEGIN
+select * into o from orders where orderId = l_orderId and status = 'open' for update;
+if not found then
+  return false;
+end if;
+update wallet set walletbalance = walletbalance + o.reserved where userId = o.userId and serverId = o.serverId;
+update orders set status = l_status, closedAt = NOW() where orderId = l_orderId;
+return true;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- fills an open order at l_price, writing to the same ledger as buy_currency / sell_currency. Orders pay l_fees' commission,
+-- but no spread since they fill at their own price. Neither side pays more commission than it has reserved / raised
+create function fill_limit_order(l_orderId int, l_price NUMERIC(50,10), l_fees serverfees DEFAULT NULL) returns boolean AS
+ $BODY$
+declare o orders%ROWTYPE;
+declare newTransactionId int;
+declare fee NUMERIC(25,4);
+BEGIN
+select * into o from orders where orderId = l_orderId and status = 'open' for update;
+if not found then
+  return false;
+end if;
+if (o.side = 'B') then
+  -- the reservation was made at the limit price, refund whatever the fill didn't use
+  fee := least(trade_fee(o.qty*l_price, l_fees), o.reserved - o.qty*l_price);
+  update wallet set walletbalance = walletbalance + o.reserved - o.qty*l_price - fee where userId = o.userId and serverId = o.serverId;
+  insert into transactions (userId,serverId,cryptoid,cost,buysellindicator,qty,price,fee) 
+  values (o.userId,o.serverId,o.cryptoId,o.qty*l_price,'B',o.qty,l_price,fee)
+  returning transactionId into newTransactionId;
+else
+  fee := least(trade_fee(o.qty*l_price, l_fees), o.qty*l_price);
+  update wallet set walletbalance = walletbalance + o.qty*l_price - fee where userId = o.userId and serverId = o.serverId;
+  insert into transactions (userId,serverId,cryptoid,cost,buysellindicator,qty,price,fee) 
+  values (o.userId,o.serverId,o.cryptoId,-1*o.qty*l_price,'S',-1*o.qty,l_price,fee)
+  returning transactionId into newTransactionId;
+end if;
+update orders set status = 'filled', closedAt = NOW(), fillPrice = l_price, transactionId = newTransactionId where orderId = l_orderId;
+return true;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- expires stale orders, then fills every open order at the first price tick after it was placed that crosses its limit.
+-- The l_* fees are the defaults for trade_fees. Returns the number of orders filled
+create function match_limit_orders(l_commissionPct NUMERIC DEFAULT 0, l_minFee NUMERIC DEFAULT 0, l_spreadPct NUMERIC DEFAULT 0) returns int AS
+ $BODY$
+declare o record;
+declare filled int := 0;
+BEGIN
+for o in select orderId from orders where status = 'open' and expiresAt is not null and expiresAt <= NOW() loop
+  perform cancel_limit_order(o.orderId, 'expired');
+end loop;
+for o in
+  select ord.orderId, ord.serverId, tick.price from orders ord
+  cross join lateral (
+    select c.price from cryptodata c
+    where c.id = ord.cryptoId and c.asOf > ord.createdAt
+      and ((ord.side = 'B' and c.price <= ord.limitPrice) or (ord.side = 'S' and c.price >= ord.limitPrice))
+    order by c.asOf limit 1
+  ) tick
+  where ord.status = 'open'
+  order by ord.orderId
+loop
+  if fill_limit_order(o.orderId, o.price, trade_fees(o.serverId, l_commissionPct, l_minFee, l_spreadPct)) then
+    filled := filled + 1;
+  end if;
+end loop;
+return filled;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- attaches a stop-loss / take-profit trigger to a position the user currently holds. Returns the new triggerId
+create function place_price_trigger(l_userId VARCHAR(256), l_serverId VARCHAR(256), l_cryptoId VARCHAR(256), l_kind VARCHAR(16), l_triggerPrice NUMERIC(50,10), l_qty NUMERIC(25,8)) returns int AS
+ $BODY$
+declare ownedAmnt numeric(50,10) := 0.0;
+declare newTriggerId int;
+BEGIN
+if (l_qty is not null and l_qty <= 0) then 
+ raise exception 'Qty must be a positive decimal!';
+end if;
+if (l_triggerPrice <= 0) then 
+ raise exception 'Trigger price must be a positive decimal!';
+end if;
+select SUM(t.qty) into ownedAmnt from transactions t where t.userid = l_userId and t.serverid = l_serverId and t.cryptoid = l_cryptoId;
+if (ownedAmnt is null or ownedAmnt <= 0) then
+     raise exception 'Insufficient funds';
+end if;
+insert into pricetriggers (userId,serverId,cryptoId,kind,triggerPrice,qty)
+values (l_userId,l_serverId,l_cryptoId,l_kind,l_triggerPrice,l_qty)
+returning triggerId into newTriggerId;
+return newTriggerId;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- cancels triggers on positions that have been closed, then market sells every position whose latest price
+-- (newer than the trigger) crossed an active trigger. The l_* fees are the defaults for trade_fees. Returns the number of
+-- triggers fired
+create function fire_price_triggers(l_commissionPct NUMERIC DEFAULT 0, l_minFee NUMERIC DEFAULT 0, l_spreadPct NUMERIC DEFAULT 0) returns int AS
+ $BODY$
+declare tr record;
+declare sellable numeric(50,10);
+declare sellQty numeric(50,10);
+declare fired int := 0;
+declare newTransactionId int;
+BEGIN
+update pricetriggers pt set status = 'cancelled', closedAt = NOW(), closeReason = 'Position no longer held'
+where pt.status = 'active'
+  and coalesce((select SUM(t.qty) from transactions t where t.userId = pt.userId and t.serverId = pt.serverId and t.cryptoId = pt.cryptoId), 0) <= 0;
+for tr in
+  select pt.*, lp.price from pricetriggers pt
+  cross join lateral (
+    select c.price, c.asOf from cryptodata c where c.id = pt.cryptoId order by c.asOf desc limit 1
+  ) lp
+  where pt.status = 'active' and lp.asOf > pt.createdAt
+    and ((pt.kind = 'stop_loss' and lp.price <= pt.triggerPrice) or (pt.kind = 'take_profit' and lp.price >= pt.triggerPrice))
+  order by pt.triggerId
+loop
+  -- coins held for open sell orders can't be sold by a trigger either
+  select coalesce(SUM(t.qty), 0) - coalesce((select SUM(o.qty) from orders o
+    where o.userId = tr.userId and o.serverId = tr.serverId and o.cryptoId = tr.cryptoId and o.side = 'S' and o.status = 'open'), 0)
+  into sellable from transactions t where t.userId = tr.userId and t.serverId = tr.serverId and t.cryptoId = tr.cryptoId;
+  sellQty := least(coalesce(tr.qty, sellable), sellable);
+  if (sellQty <= 0) then
+    update pricetriggers set status = 'cancelled', closedAt = NOW(), closeReason = 'Position no longer held' where triggerId = tr.triggerId;
+    continue;
+  end if;
+  begin
+    newTransactionId := sell_currency(sellQty, tr.cryptoId, tr.userId, tr.serverId, NULL, NULL, NULL, trade_fees(tr.serverId, l_commissionPct, l_minFee, l_spreadPct));
+    update pricetriggers set status = 'fired', closedAt = NOW(), firedPrice = tr.price, transactionId = newTransactionId
+    where triggerId = tr.triggerId;
+    fired := fired + 1;
+  exception when raise_exception then
+    update pricetriggers set status = 'cancelled', closedAt = NOW(), closeReason = SQLERRM where triggerId = tr.triggerId;
+  end;
+end loop;
+return fired;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- a wallet's net worth at every l_step from l_from to l_to. Cash is worked back from the current balance by undoing
+-- later trades, daily rewards and buy order reservations, plus what buy orders open then held back, like vNetworth. Holdings
+-- are valued at the last price known at each step
+create function networth_history(l_userId VARCHAR(256), l_serverId VARCHAR(256), l_from TIMESTAMP, l_to TIMESTAMP, l_step INTERVAL)
+returns table (asOf TIMESTAMP, netWorth NUMERIC) AS
+ $BODY$
+select s.asOf,
+  w.walletBalance
+  + coalesce((select SUM(t.cost + t.fee) from transactions t
+      where t.userId = l_userId and t.serverId = l_serverId and t.transactionTime > s.asOf), 0)
+  - coalesce((select SUM(d.amount) from dailyrewards d
+      where d.userId = l_userId and d.serverId = l_serverId and d.claimedAt > s.asOf), 0)
+  + coalesce((select SUM(o.reserved) from orders o
+      where o.userId = l_userId and o.serverId = l_serverId and o.createdAt > s.asOf), 0)
+  - coalesce((select SUM(o.reserved) from orders o
+      where o.userId = l_userId and o.serverId = l_serverId and o.closedAt > s.asOf), 0)
+  + coalesce((select SUM(o.reserved) from orders o
+      where o.userId = l_userId and o.serverId = l_serverId and o.createdAt <= s.asOf and (o.closedAt is null or o.closedAt > s.asOf)), 0)
+  + coalesce((select SUM(h.qty * (select c.price from cryptodata c where c.id = h.cryptoId and c.asOf <= s.asOf order by c.asOf desc limit 1))
+      from (
+        select t.cryptoId, SUM(t.qty) as qty from transactions t
+        where t.userId = l_userId and t.serverId = l_serverId and t.transactionTime <= s.asOf
+        group by t.cryptoId
+      ) h), 0)
+from generate_series(l_from, l_to, l_step) as s(asOf)
+join wallet w on w.userId = l_userId and w.serverId = l_serverId
+order by s.asOf;
+$BODY$
+ LANGUAGE sql STABLE;
+
+-- snapshots every wallet into networthsnapshots for the l_every sized interval NOW() falls in (intervals are aligned to the
+-- unix epoch). Wallets already snapshotted in this interval are skipped. Returns the number of snapshots written
+create function snapshot_networth(l_every INTERVAL) returns int AS
+ $BODY$
+declare everySecs double precision := extract(epoch from l_every);
+declare l_snapshotAt TIMESTAMP := to_timestamp(floor(extract(epoch from NOW()::TIMESTAMP) / everySecs) * everySecs) at time zone 'UTC';
+declare written int;
+BEGIN
+insert into networthsnapshots (userId,serverId,snapshotAt,cash,portfolioValue,netWorth)
+select n.userId, n.serverId, l_snapshotAt, n.walletBalance + n.reserved, n.portfolioValue, n.netWorth
+from vNetworth n
+on conflict do nothing;
+get diagnostics written = row_count;
+return written;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- trades a quote at its price on behalf of l_userId. Each quote can only be used once and only before it expires.
+-- The quoted price already includes the spread, so only l_fees' commission is charged. Returns the new transactionId
+create function execute_quote(l_quoteId int, l_userId VARCHAR(256), l_side CHAR(1), l_fees serverfees DEFAULT NULL) returns int AS
+ $BODY$
+declare q quotes%ROWTYPE;
+declare newTransactionId int;
+BEGIN
+select * into q from quotes where quoteId = l_quoteId for update;
+if (not found or q.userId <> l_userId) then
+  raise exception 'Could not find quote %', l_quoteId;
+end if;
+if (q.side <> l_side) then
+  raise exception 'Quote % is for the other side of the trade', l_quoteId;
+end if;
+if (q.usedAt is not null) then
+  raise exception 'Quote already used: % was executed at % UTC', l_quoteId, to_char(q.usedAt, 'YYYY-MM-DD HH24:MI:SS');
+end if;
+if (q.expiresAt <= NOW()::TIMESTAMP) then
+  raise exception 'Quote expired: % expired at % UTC', l_quoteId, to_char(q.expiresAt, 'YYYY-MM-DD HH24:MI:SS');
+end if;
+if (q.side = 'B') then
+  newTransactionId := buy_currency(q.qty, q.cryptoId, q.userId, q.serverId, NULL, q.price, NULL, l_fees);
+else
+  newTransactionId := sell_currency(q.qty, q.cryptoId, q.userId, q.serverId, NULL, q.price, NULL, l_fees);
+end if;
+update quotes set usedAt = NOW(), transactionId = newTransactionId where quoteId = l_quoteId;
+return newTransactionId;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- hash stored in apikeys.keyHash for a key's secret, the part after the '.'
+create function hash_api_key(l_salt BYTEA, l_secret TEXT) returns BYTEA AS
+ $BODY$
+select sha256(l_salt || convert_to(l_secret, 'UTF8'));
+$BODY$
+ LANGUAGE sql IMMUTABLE;
+
+-- 64 random hex digits, for key and signing secrets
+create function random_api_key_secret() returns TEXT AS
+ $BODY$
+select replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '');
+$BODY$
+ LANGUAGE sql VOLATILE;
+
+-- makes an API key with l_scopes and returns it as '<id>.<secret>'. The secret isn't stored, so this is the only time
+-- it can be read. l_actorKeyId is the admin key recorded in apikeyaudit as making it. NULL limits use the defaults.
+-- l_signed keys get a signingSecret their requests have to be signed with
+create function create_api_key(l_scopes VARCHAR(32)[], l_description VARCHAR(1024) DEFAULT NULL, l_actorKeyId int DEFAULT NULL, l_readLimit int DEFAULT NULL, l_tradeLimit int DEFAULT NULL, l_signed boolean DEFAULT false) returns TEXT AS
+ $BODY$
+declare l_secret TEXT := random_api_key_secret();
+declare l_salt BYTEA := decode(replace(gen_random_uuid()::text, '-', ''), 'hex');
+declare newId int;
+BEGIN
+insert into apikeys (salt,keyHash,scopes,description,readLimit,tradeLimit,signingSecret)
+values (l_salt, hash_api_key(l_salt, l_secret), l_scopes, l_description, l_readLimit, l_tradeLimit, case when l_signed then random_api_key_secret() end)
+returning id into newId;
+insert into apikeyaudit (keyId,action,actorKeyId,detail)
+values (newId, 'created', l_actorKeyId, 'scopes ' || array_to_string(l_scopes, ',') || case when l_signed then ', signed' else '' end);
+return newId || '.' || l_secret;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- replaces a working key with a new one that has the same scopes, description and limits, returned as '<id>.<secret>'. The old
+-- key keeps working for l_grace (or until it was already due to expire). If the old key was signed, the new one gets its
+-- own signingSecret. Returns NULL if the key is revoked or expired
+create function rotate_api_key(l_keyId int, l_grace INTERVAL, l_actorKeyId int DEFAULT NULL) returns TEXT AS
+ $BODY$
+declare k apikeys%ROWTYPE;
+declare newKey TEXT;
+declare l_expiresAt TIMESTAMP;
+BEGIN
+select * into k from apikeys where id = l_keyId and revokedAt is null and (expiresAt is null or expiresAt > NOW()) for update;
+if not found then
+  return null;
+end if;
+newKey := create_api_key(k.scopes, k.description, l_actorKeyId, k.readLimit, k.tradeLimit, k.signingSecret is not null);
+l_expiresAt := least(coalesce(k.expiresAt, 'infinity'), NOW() + l_grace);
+update apikeys set expiresAt = l_expiresAt where id = l_keyId;
+insert into apikeyaudit (keyId,action,actorKeyId,detail)
+values (l_keyId, 'rotated', l_actorKeyId, 'replaced by key ' || split_part(newKey, '.', 1) || ', expires at ' || to_char(l_expiresAt, 'YYYY-MM-DD HH24:MI:SS'));
+return newKey;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- stops a key from working straight away. Returns false if it was already revoked
+create function revoke_api_key(l_keyId int, l_actorKeyId int DEFAULT NULL) returns boolean AS
+ $BODY$
+BEGIN
+update apikeys set revokedAt = NOW() where id = l_keyId and revokedAt is null;
+if not found then
+  return false;
+end if;
+insert into apikeyaudit (keyId,action,actorKeyId) values (l_keyId, 'revoked', l_actorKeyId);
+return true;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- sets how many requests a key may make per rate limit window. NULL limits use the defaults
+create function set_api_key_limits(l_keyId int, l_readLimit int, l_tradeLimit int, l_actorKeyId int DEFAULT NULL) returns void AS
+ $BODY$
+BEGIN
+update apikeys set readLimit = l_readLimit, tradeLimit = l_tradeLimit where id = l_keyId;
+insert into apikeyaudit (keyId,action,actorKeyId,detail)
+values (l_keyId, 'limits', l_actorKeyId, 'read ' || coalesce(l_readLimit::text, 'default') || ', trade ' || coalesce(l_tradeLimit::text, 'default'));
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- makes a key need signed requests, with a new signingSecret that is returned, or stops it needing them and returns NULL
+create function set_api_key_signing(l_keyId int, l_signed boolean, l_actorKeyId int DEFAULT NULL) returns TEXT AS
+ $BODY$
+declare l_signingSecret TEXT := case when l_signed then random_api_key_secret() end;
+BEGIN
+update apikeys set signingSecret = l_signingSecret where id = l_keyId;
+insert into apikeyaudit (keyId,action,actorKeyId,detail)
+values (l_keyId, 'signing', l_actorKeyId, case when l_signed then 'new signing secret' else 'unsigned' end);
+return l_signingSecret;
+end $BODY$
+ LANGUAGE 'plpgsql' 
+COST 100;
+
+-- the API LISTENs on apikeys_changed and reloads its keys, so added and removed keys apply without a restart
+create function notify_apikeys_changed() returns trigger AS
+ $BODY$
+BEGIN
+perform pg_notify('apikeys_changed', '');
+return null;
+end $BODY$
+ LANGUAGE 'plpgsql';
+
+-- lastUsedAt is left out, so recording key use doesn't make every server reload
+CREATE TRIGGER TRG_apikeys_changed AFTER INSERT OR UPDATE OF salt, keyHash, scopes, expiresAt, revokedAt, readLimit, tradeLimit, signingSecret OR DELETE OR TRUNCATE ON apikeys
+FOR EACH STATEMENT EXECUTE FUNCTION notify_apikeys_changed();
+
+-- I will finish leaderboards later
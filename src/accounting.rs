@@ -0,0 +1,143 @@
+//! Cost basis and realized profit, worked out by replaying the `transactions` ledger
+
+use std::collections::{HashMap, VecDeque};
+use serde::Deserialize;
+use crate::types::Numeric;
+
+/// How the cost of coins sold is matched against the coins bought
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CostBasisMethod {
+    /// Every coin held costs the average price paid for the position
+    #[default]
+    Average,
+    /// Sells use up the oldest buys first
+    Fifo
+}
+
+/// What is left of a position after replaying its trades, plus the profit already taken out of it
+#[derive(Clone,Debug,Default)]
+pub struct CostBasis {
+    /// Qty still held
+    pub qty : Numeric,
+    /// What the qty still held cost to buy
+    pub total_cost : Numeric,
+    /// Proceeds of every sell minus what the coins sold cost
+    pub realized_pnl : Numeric,
+    /// Open (qty, cost per coin) lots, oldest first. Only used for FIFO
+    lots : VecDeque<(Numeric, Numeric)>
+}
+
+impl CostBasis {
+    pub fn average_cost(&self) -> Numeric {
+        if self.qty.is_zero() { Numeric::ZERO } else { self.total_cost / self.qty }
+    }
+
+    /// Applies one ledger row. `qty` and `cost` keep the ledger's signs: positive for a buy, negative for a sell.
+    fn apply(&mut self, method : CostBasisMethod, qty : Numeric, cost : Numeric) {
+        if qty > Numeric::ZERO {
+            self.qty += qty;
+            self.total_cost += cost;
+            if method == CostBasisMethod::Fifo {
+                self.lots.push_back((qty, cost / qty));
+            }
+            return;
+        }
+        let sold = -qty;
+        let proceeds = -cost;
+        let basis = match method {
+            CostBasisMethod::Average => (self.average_cost() * sold).min(self.total_cost),
+            CostBasisMethod::Fifo => {
+                let mut remaining = sold;
+                let mut basis = Numeric::ZERO;
+                while remaining > Numeric::ZERO {
+                    let (lot_qty, unit_cost) = match self.lots.front_mut() {
+                        Some(lot) => lot,
+                        None => break
+                    };
+                    let used = remaining.min(*lot_qty);
+                    basis += used * *unit_cost;
+                    *lot_qty -= used;
+                    remaining -= used;
+                    if lot_qty.is_zero() {
+                        self.lots.pop_front();
+                    }
+                }
+                basis
+            }
+        };
+        self.qty -= sold;
+        self.total_cost -= basis;
+        self.realized_pnl += proceeds - basis;
+        if self.qty.is_zero() {
+            self.total_cost = Numeric::ZERO;
+        }
+    }
+}
+
+/// Replays `(cryptoId, qty, cost)` ledger rows, which must be in the order they were made, into a basis per coin. Fees
+/// should already be in `cost`.
+pub fn cost_basis<I>(method : CostBasisMethod, ledger : I) -> HashMap<String, CostBasis>
+where
+    I : IntoIterator<Item = (String, Numeric, Numeric)>
+{
+    let mut positions : HashMap<String, CostBasis> = HashMap::new();
+    for (crypto_id, qty, cost) in ledger {
+        positions.entry(crypto_id).or_default().apply(method, qty, cost);
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(method : CostBasisMethod, trades : &[(i64, i64)]) -> CostBasis {
+        let ledger = trades.iter().map(|&(qty, cost)| (String::from("coin"), Numeric::from(qty), Numeric::from(cost)));
+        cost_basis(method, ledger).remove("coin").unwrap()
+    }
+
+    struct Case {
+        name : &'static str,
+        method : CostBasisMethod,
+        /// (qty, cost), negative for sells
+        trades : &'static [(i64, i64)],
+        qty : i64,
+        total_cost : i64,
+        average_cost : i64,
+        realized_pnl : i64
+    }
+
+    #[test]
+    fn replays_trades() {
+        use CostBasisMethod::*;
+        let cases = [
+            Case { name : "partial sell, FIFO sells the 100 lot", method : Fifo, trades : &[(1, 100), (1, 200), (-1, -300)], qty : 1, total_cost : 200, average_cost : 200, realized_pnl : 200 },
+            Case { name : "partial sell, average cost is 150", method : Average, trades : &[(1, 100), (1, 200), (-1, -300)], qty : 1, total_cost : 150, average_cost : 150, realized_pnl : 150 },
+            Case { name : "sold out then bought again, FIFO", method : Fifo, trades : &[(2, 200), (-2, -150), (1, 50)], qty : 1, total_cost : 50, average_cost : 50, realized_pnl : -50 },
+            Case { name : "sold out then bought again, average", method : Average, trades : &[(2, 200), (-2, -150), (1, 50)], qty : 1, total_cost : 50, average_cost : 50, realized_pnl : -50 },
+            Case { name : "loss is negative", method : Average, trades : &[(1, 100), (-1, -80)], qty : 0, total_cost : 0, average_cost : 0, realized_pnl : -20 },
+            Case { name : "gain is positive", method : Fifo, trades : &[(1, 100), (-1, -130)], qty : 0, total_cost : 0, average_cost : 0, realized_pnl : 30 },
+            Case { name : "gains and losses net out, FIFO", method : Fifo, trades : &[(1, 100), (1, 300), (-1, -200), (-1, -200)], qty : 0, total_cost : 0, average_cost : 0, realized_pnl : 0 }
+        ];
+        for case in &cases {
+            let basis = replay(case.method, case.trades);
+            assert_eq!(basis.qty, Numeric::from(case.qty), "{}: qty", case.name);
+            assert_eq!(basis.total_cost, Numeric::from(case.total_cost), "{}: total cost", case.name);
+            assert_eq!(basis.average_cost(), Numeric::from(case.average_cost), "{}: average cost", case.name);
+            assert_eq!(basis.realized_pnl, Numeric::from(case.realized_pnl), "{}: realized P&L", case.name);
+        }
+    }
+
+    #[test]
+    fn keeps_coins_apart() {
+        let ledger = vec![
+            (String::from("a"), Numeric::from(1), Numeric::from(10)),
+            (String::from("b"), Numeric::from(2), Numeric::from(40)),
+            (String::from("a"), Numeric::from(-1), Numeric::from(-15))
+        ];
+        let positions = cost_basis(CostBasisMethod::Fifo, ledger);
+        assert_eq!(positions["a"].realized_pnl, Numeric::from(5));
+        assert_eq!(positions["b"].average_cost(), Numeric::from(20));
+    }
+}
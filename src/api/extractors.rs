@@ -0,0 +1,46 @@
+// Extractors shared by the routes
+
+use std::future::{Future, ready};
+use std::ops::Deref;
+use std::pin::Pin;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, web, dev::Payload};
+use serde::de::DeserializeOwned;
+
+/// Left in the request extensions when a `JsonOrQuery` was read from the query string, so
+/// `middlewares::deprecation` can flag the response
+pub struct QueryForm;
+
+/// Whether a request says its body is JSON, the same way `web::Json` checks
+pub fn is_json(req : &impl HttpMessage) -> bool {
+  let content_type = req.content_type();
+  content_type == "application/json" || content_type.ends_with("+json")
+}
+
+/// Reads a mutating request from its JSON body, or from the query string (deprecated) when it wasn't sent as JSON
+pub struct JsonOrQuery<T>(pub T);
+
+impl<T> Deref for JsonOrQuery<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.0
+  }
+}
+
+impl<T : DeserializeOwned + 'static> FromRequest for JsonOrQuery<T> {
+  type Error = Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+  type Config = ();
+
+  fn from_request(req : &HttpRequest, payload : &mut Payload) -> Self::Future {
+    if is_json(req) {
+      let json = web::Json::<T>::from_request(req, payload);
+      return Box::pin(async move { Ok(JsonOrQuery(json.await?.into_inner())) });
+    }
+    let query = web::Query::<T>::from_query(req.query_string()).map_err(Error::from);
+    if query.is_ok() {
+      req.extensions_mut().insert(QueryForm);
+    }
+    Box::pin(ready(query.map(|q| JsonOrQuery(q.into_inner()))))
+  }
+}
@@ -1,2 +1,3 @@
+pub mod extractors;
 pub mod routes;
 pub mod types;
\ No newline at end of file
@@ -1,6 +1,19 @@
-use actix_web::{get, Responder, HttpResponse, web, post, put};
+use actix_web::{get, Responder, HttpRequest, HttpResponse, web, post, put, delete, http::header};
+use rust_decimal::RoundingStrategy;
+use rust_decimal::prelude::ToPrimitive;
+use sha2::{Digest, Sha256};
 use crate::types::{*};
+use crate::errors::{BrokerError,BrokerResult};
+use crate::graphs::{Chart, Series, render_line_chart};
 use super::types::{*};
+use super::extractors::JsonOrQuery;
+use crate::middlewares::apikey::RequireScope;
+use crate::apikeys::AuthenticatedKey;
+
+/// Upper bound on the number of buckets a time series endpoint will return
+const MAX_SERIES_POINTS : i64 = 5000;
+/// Graphs widen the default resolution of their window so no line has more points than this
+const MAX_GRAPH_POINTS : i64 = 500;
 
 macro_rules! json_ok {
   ($e : expr) => {
@@ -8,78 +21,431 @@ macro_rules! json_ok {
   };
 }
 
-#[get("/list")]
-pub async fn list(state : web::Data<RootAppState>) -> StdResult<impl Responder> {
+#[get("/status", wrap = "RequireScope::READ")]
+pub async fn status(state : web::Data<RootAppState>) -> BrokerResult<impl Responder> {
+  let newest_quote_at = state.broker_mapper.newest_quote_at().await?;
+  let newest_quote_age_secs = newest_quote_at.map(|ts| (chrono::Utc::now() - ts).num_seconds());
+  let max_quote_age_secs = state.broker_mapper.max_quote_age().map(|age| age.as_secs());
+  let prices_stale = match (newest_quote_age_secs, max_quote_age_secs) {
+    (None, _) => true,
+    (Some(age), Some(max_age)) => age > max_age as i64,
+    (Some(_), None) => false
+  };
+  json_ok!(ServiceStatus {
+    success : true,
+    newest_quote_at,
+    newest_quote_age_secs,
+    max_quote_age_secs,
+    prices_stale,
+    ingestion_enabled : state.config.ingest.enabled,
+    auth_mode : state.config.api_keys.auth_mode
+  })
+}
+
+#[get("/list", wrap = "RequireScope::READ")]
+pub async fn list(state : web::Data<RootAppState>) -> BrokerResult<impl Responder> {
   json_ok!(state.broker_mapper.list_currencies().await?)
 }
 
-#[get("/balance")]
-pub async fn balance(state : web::Data<RootAppState>, params : web::Query<GetWalletBalanceRequest>) -> StdResult<impl Responder> {
+#[get("/balance", wrap = "RequireScope::READ")]
+pub async fn balance(state : web::Data<RootAppState>, params : web::Query<GetWalletBalanceRequest>) -> BrokerResult<impl Responder> {
   // TODO: Have a way to indicate the difference between a non-existant wallet and an actual error.
-  let balance = state.broker_mapper.get_wallet_balance_by_userid(&params.user_id).await?;
-  json_ok!(GetWalletBalanceResponse { user_id : params.user_id.clone(), balance })
+  let balance = state.broker_mapper.get_wallet_balance_by_userid(&params.user_id, params.server_id.as_deref()).await?;
+  json_ok!(GetWalletBalanceResponse { user_id : params.user_id.clone(), server_id : params.server_id.clone(), balance })
 }
 
-#[get("/portfolio")]
-pub async fn get_portfolio(state : web::Data<RootAppState>, params : web::Query<GetPortfolioRequest>) -> StdResult<impl Responder> {
-  json_ok!(state.broker_mapper.get_portfolio(&params.user_id).await?)
+#[get("/portfolio", wrap = "RequireScope::READ")]
+pub async fn get_portfolio(state : web::Data<RootAppState>, params : web::Query<GetPortfolioRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.get_portfolio(&params.user_id, params.server_id.as_deref(), params.accounting).await?)
 }
 
-#[post("/buy")]
-pub async fn buy_currency(state : web::Data<RootAppState>, params : web::Query<CoinTransactionRequest>) -> StdResult<impl Responder> {
-  let coin = match coin_from_key(&state, &params.coin_key).await {
-    Ok(c) => c, Err(resp) => return Ok(resp)
+#[post("/buy", wrap = "RequireScope::TRADE")]
+pub async fn buy_currency(state : web::Data<RootAppState>, params : JsonOrQuery<CoinTransactionRequest>) -> BrokerResult<HttpResponse> {
+  let receipt = match &params.quote_id {
+    Some(quote_id) => execute_quote(&state, quote_id, &params, OrderSide::Buy).await?,
+    None => {
+      let size = trade_size(&params, OrderSide::Buy)?;
+      let coin = match coin_from_key(&state, &params.coin_key).await? {
+        Ok(c) => c, Err(resp) => return Ok(resp)
+      };
+      state.broker_mapper.buy_currency(&coin.id, size, &params.user_id, params.server_id.as_deref()).await?
+    }
+  };
+  json_ok!(CoinTransactionResponse{msg:String::from("Success"), currencies: None, receipt: Some(receipt)})
+}
+
+#[post("/sell", wrap = "RequireScope::TRADE")]
+pub async fn sell_currency(state : web::Data<RootAppState>, params : JsonOrQuery<CoinTransactionRequest>) -> BrokerResult<HttpResponse> {
+  let receipt = match &params.quote_id {
+    Some(quote_id) => execute_quote(&state, quote_id, &params, OrderSide::Sell).await?,
+    None => {
+      let size = trade_size(&params, OrderSide::Sell)?;
+      let coin = match coin_from_key(&state, &params.coin_key).await? {
+        Ok(c) => c, Err(resp) => return Ok(resp)
+      };
+      state.broker_mapper.sell_currency(&coin.id, size, &params.user_id, params.server_id.as_deref()).await?
+    }
   };
-  state.broker_mapper.buy_currency(&coin.id,&params.qty,&params.user_id).await?;
-  Ok((web::Json(CoinTransactionResponse{msg:String::from("Success"),currencies:None}),actix_web::http::StatusCode::OK))
+  json_ok!(CoinTransactionResponse{msg:String::from("Success"), currencies: None, receipt: Some(receipt)})
 }
 
-#[post("/sell")]
-pub async fn sell_currency(state : web::Data<RootAppState>, params : web::Query<CoinTransactionRequest>) -> StdResult<impl Responder> {
-  let coin = match coin_from_key(&state, &params.coin_key).await {
+/// Reads how much a `/buy` or `/sell` without a quote trades from exactly one of `qty` and `amount`
+fn trade_size(params : &CoinTransactionRequest, side : OrderSide) -> BrokerResult<TradeSize> {
+  match (params.qty, params.amount) {
+    (Some(_), Some(_)) => Err(BrokerError::Validation(String::from("Send either qty or amount, not both"))),
+    (Some(PositionQty::All), None) if side == OrderSide::Buy => Err(BrokerError::Validation(String::from("qty=all can only be sold"))),
+    (Some(PositionQty::All), None) => Ok(TradeSize::All),
+    (Some(PositionQty::Qty(qty)), None) => Ok(TradeSize::Qty(qty)),
+    (None, Some(amount)) => Ok(TradeSize::Amount(amount)),
+    (None, None) => Err(BrokerError::Validation(String::from("qty, amount or quoteId is required")))
+  }
+}
+
+/// Trades a quote from `POST /quotes` after checking its signature. Quotes belonging to someone else are reported as missing
+async fn execute_quote(state : &web::Data<RootAppState>, quote_id : &str, params : &CoinTransactionRequest, side : OrderSide) -> BrokerResult<TradeReceipt> {
+  if params.qty.is_some() || params.amount.is_some() {
+    return Err(BrokerError::Validation(String::from("A quote already sets the qty, so send quoteId without qty or amount")));
+  }
+  let user_id = params.user_id.as_str();
+  let (id, signature) = crate::quotes::QuoteSigner::parse(quote_id)?;
+  let quote = state.broker_mapper.quote_by_id(id).await?;
+  state.quote_signer.verify(&quote, &signature)?;
+  if quote.user_id != user_id {
+    return Err(BrokerError::NotFound(format!("Could not find quote {}", id)));
+  }
+  state.broker_mapper.execute_quote(&quote, user_id, side).await
+}
+
+#[post("/quotes", wrap = "RequireScope::TRADE")]
+pub async fn place_quote(state : web::Data<RootAppState>, request : web::Json<PlaceQuoteRequest>) -> BrokerResult<HttpResponse> {
+  if request.qty <= Numeric::ZERO {
+    return Err(BrokerError::Validation(String::from("qty must be positive")));
+  }
+  check_qty_scale(request.qty)?;
+  let coin = match coin_from_key(&state, &request.coin_key).await? {
     Ok(c) => c, Err(resp) => return Ok(resp)
   };
-  state.broker_mapper.sell_currency(&coin.id, &params.qty, &params.user_id).await?;
-  Ok((web::Json(CoinTransactionResponse{msg:String::from("Success"), currencies: None}),actix_web::http::StatusCode::OK))
+  if coin.stale {
+    return Err(BrokerError::StalePrice(format!("Stale price for {}: the newest quote is from {} UTC", coin.id, coin.as_of.format("%Y-%m-%d %H:%M:%S"))));
+  }
+  let record = state.broker_mapper.create_quote(&request, &coin.id, &coin.price, state.config.quotes.ttl_secs).await?;
+  Ok(HttpResponse::Created().json(Quote {
+    quote_id : state.quote_signer.quote_id(&record),
+    total : record.qty * record.price,
+    crypto_id : record.crypto_id,
+    side : record.side,
+    qty : record.qty,
+    price : record.price,
+    expires_at : record.expires_at
+  }))
+}
+
+/// The ledger keeps 8 decimal places of qty. Postgres would round anything past them rather than refuse it
+fn check_qty_scale(qty : Numeric) -> BrokerResult<()> {
+  if qty != qty.round_dp_with_strategy(8, RoundingStrategy::ToZero) {
+    return Err(BrokerError::Validation(String::from("Qty can have at most 8 decimal places!")));
+  }
+  Ok(())
 }
 
 #[inline(always)]
-/// Resolves a coin identifer tuple to either a response (in the case that the identifier is ambiguous or no coin found), or a coin if exactly one could be found with the 
-/// information present
-async fn coin_from_key(state : &web::Data<RootAppState>, coin_key : &CoinIdentifierKey) -> Result<CurrencyData, (web::Json<CoinTransactionResponse>,actix_web::http::StatusCode)>{
-  let coins_res : StdResult<Vec<CurrencyData>> = state.broker_mapper.get_coins_matching_key(coin_key).await;
-  match coins_res {
-    Ok(mut coins) => {
-      if coins.is_empty() {
-        return Err((web::Json(CoinTransactionResponse{msg:String::from("No coin found matching criteria!"),currencies:None}),actix_web::http::StatusCode::BAD_REQUEST));
-      }
-      if coins.len() > 1 {
-        return Err((web::Json(CoinTransactionResponse{msg:String::from("Multiple coins found!"),currencies: Some(coins)}),actix_web::http::StatusCode::MULTIPLE_CHOICES));
-      }
-      return Ok(coins.remove(0));
-    },
-    Err(_) => {
-      return Err((web::Json(CoinTransactionResponse{msg:String::from(format!("Failed retrieving coins for identifer tuple {:?}",coin_key)),currencies:None}),actix_web::http::StatusCode::INTERNAL_SERVER_ERROR));
-    }
+/// Resolves a coin identifer tuple to either a coin if exactly one could be found with the information present, or a
+/// 300 response listing the candidates when the identifier is ambiguous. Finding no coin at all is an error.
+async fn coin_from_key(state : &web::Data<RootAppState>, coin_key : &CoinIdentifierKey) -> BrokerResult<Result<CurrencyData, HttpResponse>> {
+  let mut coins = state.broker_mapper.get_coins_matching_key(coin_key).await?;
+  if coins.is_empty() {
+    return Err(BrokerError::NotFound(String::from("No coin found matching criteria!")));
+  }
+  if coins.len() > 1 {
+    return Ok(Err(HttpResponse::MultipleChoices().json(CoinTransactionResponse{msg:String::from("Multiple coins found!"),currencies: Some(coins),receipt:None})));
   }
+  Ok(Ok(coins.remove(0)))
 }
 
-#[get("/coin")]
-pub async fn get_coin(state : web::Data<RootAppState>, params : web::Query<CoinIdentifierKey>) -> StdResult<impl Responder> {
-  return json_ok!(state.broker_mapper.get_coins_matching_key(&params).await?);
+#[get("/coin", wrap = "RequireScope::READ")]
+pub async fn get_coin(state : web::Data<RootAppState>, params : web::Query<CoinIdentifierKey>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.get_coins_matching_key(&params).await?)
 }
 
-#[post("/daily-reward")]
-pub async fn daily_reward(state : web::Data<RootAppState>, request : web::Query<DailyRewardRequest>) -> StdResult<impl Responder> {
-  // TODO: In a single query only allow the user to increase his balance once daily.
-  let curr_balance = state.broker_mapper.get_wallet_balance_by_userid(&request.user_id).await.unwrap_or(0.into());
-  let new_balance = curr_balance + &100_i32.into();
-  state.broker_mapper.set_wallet_balance_by_userid(&request.user_id, new_balance).await?;
-  json_ok!(StatusResponse::ok())
+#[post("/daily-reward", wrap = "RequireScope::TRADE")]
+pub async fn daily_reward(state : web::Data<RootAppState>, request : JsonOrQuery<DailyRewardRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.claim_daily_reward(&request.user_id, request.server_id.as_deref(), &state.config.daily_reward).await?)
 }
 
-#[put("/leaderboard")]
-pub async fn update_server_members(state : web::Data<RootAppState>, request : web::Json<UpdateServerMembersRequest>) -> StdResult<impl Responder> {
+#[put("/leaderboard", wrap = "RequireScope::LEADERBOARD_WRITE")]
+pub async fn update_server_members(state : web::Data<RootAppState>, request : web::Json<UpdateServerMembersRequest>) -> BrokerResult<impl Responder> {
   state.broker_mapper.update_server_patrons(&request.user_ids, &request.server_id).await?;
   json_ok!(StatusResponse::ok())
 }
+
+#[get("/fees", wrap = "RequireScope::READ")]
+pub async fn get_fees(state : web::Data<RootAppState>, params : web::Query<GetFeesRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.trade_fees(params.server_id.as_deref()).await?)
+}
+
+#[put("/fees", wrap = "RequireScope::ADMIN")]
+pub async fn update_fees(state : web::Data<RootAppState>, request : web::Json<UpdateFeesRequest>) -> BrokerResult<impl Responder> {
+  if request.server_id.is_empty() {
+    return Err(BrokerError::Validation(String::from("serverId is required, the defaults are set with CB_FEE_* variables")));
+  }
+  let pct_ok = |pct : Option<Numeric>, max : i64| pct.is_none_or(|p| p >= Numeric::ZERO && p < Numeric::from(max));
+  if !pct_ok(request.commission_pct, 100) || !pct_ok(request.spread_pct, 200) || request.min_fee.is_some_and(|fee| fee < Numeric::ZERO) {
+    return Err(BrokerError::Validation(String::from("commissionPct must be 0 to under 100, spreadPct 0 to under 200 and minFee at least 0")));
+  }
+  json_ok!(state.broker_mapper.set_server_fees(&request).await?)
+}
+
+#[get("/apikeys", wrap = "RequireScope::ADMIN")]
+pub async fn list_api_keys(state : web::Data<RootAppState>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.list_api_keys().await?)
+}
+
+#[post("/apikeys", wrap = "RequireScope::ADMIN")]
+pub async fn create_api_key(state : web::Data<RootAppState>, key : web::ReqData<AuthenticatedKey>, request : web::Json<CreateApiKeyRequest>) -> BrokerResult<HttpResponse> {
+  if request.scopes.is_empty() {
+    return Err(BrokerError::Validation(String::from("A key needs at least one scope")));
+  }
+  check_api_key_limits(&request.limits())?;
+  Ok(HttpResponse::Created().json(state.broker_mapper.create_api_key(&request, key.id).await?))
+}
+
+#[post("/apikeys/{key_id}/rotate", wrap = "RequireScope::ADMIN")]
+pub async fn rotate_api_key(state : web::Data<RootAppState>, key : web::ReqData<AuthenticatedKey>, key_id : web::Path<i32>, request : web::Json<RotateApiKeyRequest>) -> BrokerResult<HttpResponse> {
+  let grace_secs = request.grace_period_secs.unwrap_or(state.config.api_keys.rotation_grace_secs);
+  if grace_secs < 0 {
+    return Err(BrokerError::Validation(String::from("gracePeriodSecs can't be negative")));
+  }
+  let grace = std::time::Duration::from_secs(grace_secs as u64);
+  Ok(HttpResponse::Created().json(state.broker_mapper.rotate_api_key(key_id.into_inner(), grace, key.id).await?))
+}
+
+#[put("/apikeys/{key_id}/limits", wrap = "RequireScope::ADMIN")]
+pub async fn set_api_key_limits(state : web::Data<RootAppState>, key : web::ReqData<AuthenticatedKey>, key_id : web::Path<i32>, request : web::Json<ApiKeyLimits>) -> BrokerResult<impl Responder> {
+  check_api_key_limits(&request)?;
+  json_ok!(state.broker_mapper.set_api_key_limits(key_id.into_inner(), &request, key.id).await?)
+}
+
+/// Limits are stored as INTs. A key's own limit of 0 would read as no limit at all, so that is left to the `CB_RATE_LIMIT_*` defaults
+fn check_api_key_limits(limits : &ApiKeyLimits) -> BrokerResult<()> {
+  if limits.read_limit.into_iter().chain(limits.trade_limit).any(|limit| limit == 0 || limit > i32::MAX as u32) {
+    return Err(BrokerError::Validation(format!("readLimit and tradeLimit must be 1 to {}", i32::MAX)));
+  }
+  Ok(())
+}
+
+#[put("/apikeys/{key_id}/signing", wrap = "RequireScope::ADMIN")]
+pub async fn set_api_key_signing(state : web::Data<RootAppState>, key : web::ReqData<AuthenticatedKey>, key_id : web::Path<i32>, request : web::Json<SetApiKeySigningRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.set_api_key_signing(key_id.into_inner(), request.signed, key.id).await?)
+}
+
+#[delete("/apikeys/{key_id}", wrap = "RequireScope::ADMIN")]
+pub async fn revoke_api_key(state : web::Data<RootAppState>, key : web::ReqData<AuthenticatedKey>, key_id : web::Path<i32>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.revoke_api_key(key_id.into_inner(), key.id).await?)
+}
+
+#[get("/coin/history", wrap = "RequireScope::READ")]
+pub async fn coin_history(state : web::Data<RootAppState>, params : web::Query<GetCoinHistoryRequest>) -> BrokerResult<HttpResponse> {
+  let coin = match coin_from_key(&state, &params.coin_key).await? {
+    Ok(c) => c, Err(resp) => return Ok(resp)
+  };
+  let (from, to) = params.window.bounds()?;
+  let resolution = params.resolution.unwrap_or_else(|| params.window.date_range.unwrap_or_default().default_interval());
+  if (to - from).num_seconds() / resolution.seconds() > MAX_SERIES_POINTS {
+    return Err(BrokerError::Validation(format!("That resolution would return more than {} points, pick a coarser one", MAX_SERIES_POINTS)));
+  }
+  let points = state.broker_mapper.price_history(&coin.id, &from, &to, &resolution).await?;
+  Ok(HttpResponse::Ok().json(PriceHistory {
+    crypto_id : coin.id,
+    symbol : coin.symbol,
+    name : coin.name,
+    from,
+    to,
+    resolution : resolution.seconds(),
+    points
+  }))
+}
+
+#[get("/coin/candles", wrap = "RequireScope::READ")]
+pub async fn coin_candles(state : web::Data<RootAppState>, params : web::Query<GetCandlesRequest>) -> BrokerResult<HttpResponse> {
+  let coin = match coin_from_key(&state, &params.coin_key).await? {
+    Ok(c) => c, Err(resp) => return Ok(resp)
+  };
+  let (from, to) = params.window.bounds()?;
+  let interval = params.interval.unwrap_or_else(|| params.window.date_range.unwrap_or_default().default_interval());
+  if (to - from).num_seconds() / interval.seconds() > MAX_SERIES_POINTS {
+    return Err(BrokerError::Validation(format!("That interval would return more than {} candles, pick a wider one", MAX_SERIES_POINTS)));
+  }
+  let candles = state.broker_mapper.candles(&coin.id, &from, &to, &interval).await?;
+  Ok(HttpResponse::Ok().json(CandleSeries {
+    crypto_id : coin.id,
+    symbol : coin.symbol,
+    name : coin.name,
+    interval : interval.seconds(),
+    candles
+  }))
+}
+
+#[get("/networth/history", wrap = "RequireScope::READ")]
+pub async fn networth_history(state : web::Data<RootAppState>, params : web::Query<GetNetWorthHistoryRequest>) -> BrokerResult<impl Responder> {
+  let (from, to) = params.window.bounds()?;
+  let resolution = params.resolution.unwrap_or_else(|| params.window.date_range.unwrap_or_default().default_interval());
+  // fails with not_found rather than returning an empty history for a wallet that doesn't exist
+  state.broker_mapper.get_wallet_balance_by_userid(&params.user_id, params.server_id.as_deref()).await?;
+  let snapshots = state.broker_mapper.networth_snapshots(&params.user_id, params.server_id.as_deref(), &from, &to, &resolution).await?;
+  json_ok!(NetWorthHistory::new(params.user_id.clone(), params.server_id.clone().filter(|s| !s.is_empty()), from, to, snapshots))
+}
+
+#[get("/graph/coin", wrap = "RequireScope::READ")]
+pub async fn coin_graph(req : HttpRequest, state : web::Data<RootAppState>, coin_key : web::Query<CoinIdentifierKey>, window : web::Query<TimeWindowRequest>, options : web::Query<ChartOptions>) -> BrokerResult<HttpResponse> {
+  let coin = match coin_from_key(&state, &coin_key).await? {
+    Ok(c) => c, Err(resp) => return Ok(resp)
+  };
+  let chart = chart_from_options(&options, format!("{} ({}) price", coin.name, coin.symbol.to_uppercase()))?;
+  let (from, to) = window.bounds()?;
+  let points = state.broker_mapper.price_history(&coin.id, &from, &to, &graph_resolution(&window, &from, &to)).await?;
+  let series = vec![Series {
+    label : coin.symbol.to_uppercase(),
+    points : points.iter().map(|p| (p.as_of.timestamp(), p.price.to_f64().unwrap_or_default())).collect()
+  }];
+  png_response(&req, &state, chart, series).await
+}
+
+#[get("/graph/performance", wrap = "RequireScope::READ")]
+pub async fn performance_graph(req : HttpRequest, state : web::Data<RootAppState>, params : web::Query<GetPerformanceGraphRequest>, window : web::Query<TimeWindowRequest>, options : web::Query<ChartOptions>) -> BrokerResult<HttpResponse> {
+  let chart = chart_from_options(&options, String::from("Net worth"))?;
+  let (from, to) = window.bounds()?;
+  // fails with not_found when there is no wallet to draw
+  state.broker_mapper.get_wallet_balance_by_userid(&params.user_id, params.server_id.as_deref()).await?;
+  let points = state.broker_mapper.networth_series(&params.user_id, params.server_id.as_deref(), &from, &to, &graph_resolution(&window, &from, &to)).await?;
+  let series = vec![networth_series(params.user_id.clone(), &points)];
+  png_response(&req, &state, chart, series).await
+}
+
+#[get("/graph/leaderboard", wrap = "RequireScope::READ")]
+pub async fn leaderboard_graph(req : HttpRequest, state : web::Data<RootAppState>, params : web::Query<GetLeaderboardGraphRequest>, window : web::Query<TimeWindowRequest>, options : web::Query<ChartOptions>) -> BrokerResult<HttpResponse> {
+  let limit = params.limit.unwrap_or(5);
+  if !(1..=10).contains(&limit) {
+    return Err(BrokerError::Validation(String::from("limit must be between 1 and 10")));
+  }
+  let chart = chart_from_options(&options, String::from("Leaderboard net worth"))?;
+  let (from, to) = window.bounds()?;
+  let resolution = graph_resolution(&window, &from, &to);
+  let wallet_server_id = if params.server_wallets { Some(params.server_id.as_str()) } else { None };
+  let entries = state.broker_mapper.leaderboard(&params.server_id, params.server_wallets, LeaderboardSort::Networth, limit, 0).await?;
+  let mut series = Vec::with_capacity(entries.len());
+  for entry in entries {
+    let points = state.broker_mapper.networth_series(&entry.user_id, wallet_server_id, &from, &to, &resolution).await?;
+    series.push(networth_series(entry.user_id, &points));
+  }
+  png_response(&req, &state, chart, series).await
+}
+
+fn networth_series(label : String, points : &[NetWorthPoint]) -> Series {
+  Series {
+    label,
+    points : points.iter().map(|p| (p.as_of.timestamp(), p.net_worth.to_f64().unwrap_or_default())).collect()
+  }
+}
+
+fn chart_from_options(options : &ChartOptions, title : String) -> BrokerResult<Chart> {
+  let width = options.width.unwrap_or(800);
+  let height = options.height.unwrap_or(400);
+  if !(crate::graphs::MIN_WIDTH..=crate::graphs::MAX_WIDTH).contains(&width) || !(crate::graphs::MIN_HEIGHT..=crate::graphs::MAX_HEIGHT).contains(&height) {
+    return Err(BrokerError::Validation(format!(
+      "width must be between {} and {} and height between {} and {}",
+      crate::graphs::MIN_WIDTH, crate::graphs::MAX_WIDTH, crate::graphs::MIN_HEIGHT, crate::graphs::MAX_HEIGHT
+    )));
+  }
+  Ok(Chart { title, width, height, theme : options.theme })
+}
+
+/// The window's usual resolution, widened when the window is long enough to need more than `MAX_GRAPH_POINTS`
+fn graph_resolution(window : &TimeWindowRequest, from : &chrono::DateTime<chrono::Utc>, to : &chrono::DateTime<chrono::Utc>) -> Interval {
+  let default = window.date_range.unwrap_or_default().default_interval();
+  Interval(default.seconds().max((*to - *from).num_seconds() / MAX_GRAPH_POINTS))
+}
+
+/// Renders off the async workers and answers with a cacheable PNG, or `304` when the client already has these bytes
+async fn png_response(req : &HttpRequest, state : &web::Data<RootAppState>, chart : Chart, series : Vec<Series>) -> BrokerResult<HttpResponse> {
+  let png = web::block(move || render_line_chart(&chart, &series)).await
+    .map_err(|e| BrokerError::Internal(e.to_string()))??;
+  // a hash with a fixed algorithm, so every build and instance gives a chart the same ETag
+  let etag = format!("\"{}\"", &hex::encode(Sha256::digest(&png))[..32]);
+  let cache_control = format!("public, max-age={}", state.config.graphs.cache_max_age_secs);
+  let cached = req.headers().get(header::IF_NONE_MATCH)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag));
+  let mut builder = if cached { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+  builder
+    .insert_header((header::CACHE_CONTROL, cache_control))
+    .insert_header((header::ETAG, etag));
+  if cached {
+    return Ok(builder.finish());
+  }
+  Ok(builder.content_type("image/png").body(png))
+}
+
+#[get("/leaderboard", wrap = "RequireScope::READ")]
+pub async fn leaderboard(state : web::Data<RootAppState>, params : web::Query<GetLeaderboardRequest>) -> BrokerResult<impl Responder> {
+  let limit = params.limit.unwrap_or(10);
+  let offset = params.offset.unwrap_or(0);
+  if !(1..=100).contains(&limit) || offset < 0 {
+    return Err(BrokerError::Validation(String::from("limit must be 1-100 and offset must not be negative")));
+  }
+  let entries = state.broker_mapper.leaderboard(&params.server_id, params.server_wallets, params.sort_by, limit, offset).await?;
+  json_ok!(Leaderboard { server_id : params.server_id.clone(), entries })
+}
+
+#[post("/orders", wrap = "RequireScope::TRADE")]
+pub async fn place_order(state : web::Data<RootAppState>, request : web::Json<PlaceOrderRequest>) -> BrokerResult<HttpResponse> {
+  check_qty_scale(request.qty)?;
+  let coin = match coin_from_key(&state, &request.coin_key).await? {
+    Ok(c) => c, Err(resp) => return Ok(resp)
+  };
+  if request.expires_in.is_some_and(|secs| secs <= 0) {
+    return Err(BrokerError::Validation(String::from("expires_in must be a positive number of seconds")));
+  }
+  let order = state.broker_mapper.place_limit_order(&request, &coin.id).await?;
+  Ok(HttpResponse::Created().json(order))
+}
+
+#[get("/orders", wrap = "RequireScope::READ")]
+pub async fn list_orders(state : web::Data<RootAppState>, params : web::Query<GetOrdersRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.list_orders(&params.user_id, params.server_id.as_deref(), params.status.as_deref()).await?)
+}
+
+#[delete("/orders/{order_id}", wrap = "RequireScope::TRADE")]
+pub async fn cancel_order(state : web::Data<RootAppState>, order_id : web::Path<i32>, params : JsonOrQuery<CancelOrderRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.cancel_order(order_id.into_inner(), &params.user_id).await?)
+}
+
+#[post("/triggers", wrap = "RequireScope::TRADE")]
+pub async fn place_trigger(state : web::Data<RootAppState>, request : web::Json<PlaceTriggerRequest>) -> BrokerResult<HttpResponse> {
+  if let Some(qty) = request.qty.qty() {
+    check_qty_scale(qty)?;
+  }
+  let coin = match coin_from_key(&state, &request.coin_key).await? {
+    Ok(c) => c, Err(resp) => return Ok(resp)
+  };
+  let trigger = state.broker_mapper.place_price_trigger(&request, &coin.id).await?;
+  Ok(HttpResponse::Created().json(trigger))
+}
+
+#[get("/triggers", wrap = "RequireScope::READ")]
+pub async fn list_triggers(state : web::Data<RootAppState>, params : web::Query<GetTriggersRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.list_price_triggers(&params.user_id, params.server_id.as_deref(), params.status.as_deref()).await?)
+}
+
+#[delete("/triggers/{trigger_id}", wrap = "RequireScope::TRADE")]
+pub async fn cancel_trigger(state : web::Data<RootAppState>, trigger_id : web::Path<i32>, params : JsonOrQuery<CancelOrderRequest>) -> BrokerResult<impl Responder> {
+  json_ok!(state.broker_mapper.cancel_price_trigger(trigger_id.into_inner(), &params.user_id).await?)
+}
+
+#[get("/transactions", wrap = "RequireScope::READ")]
+pub async fn list_transactions(state : web::Data<RootAppState>, params : web::Query<GetTransactionsRequest>) -> BrokerResult<impl Responder> {
+  let limit = params.limit.unwrap_or(25);
+  if !(1..=100).contains(&limit) {
+    return Err(BrokerError::Validation(String::from("limit must be 1-100")));
+  }
+  json_ok!(state.broker_mapper.list_transactions(&params, limit).await?)
+}
@@ -2,46 +2,179 @@
 
 use serde::{Deserialize,Serialize};
 use crate::types::*;
+use crate::accounting::CostBasisMethod;
+use crate::graphs::Theme;
+use crate::errors::{BrokerError,BrokerResult};
 
 #[derive(Serialize,Clone,Debug)]
 pub struct GetWalletBalanceResponse {
   pub user_id : String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
   pub balance : Numeric
 }
 
 #[derive(Deserialize,Clone,Debug)]
 pub struct GetWalletBalanceRequest {
-  pub user_id : String
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  /// Selects the wallet scoped to this server instead of the user's global wallet
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>
 }
 
 #[derive(Deserialize,Clone,Debug)]
 pub struct DailyRewardRequest {
-  pub user_id : String
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  /// Selects the wallet scoped to this server instead of the user's global wallet
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetFeesRequest {
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+/// Sets a server's fees. Fees left out use the defaults, so sending none of them removes the server's override
+pub struct UpdateFeesRequest {
+  #[serde(alias = "serverId")]
+  pub server_id : String,
+  #[serde(default, alias = "commissionPct")]
+  pub commission_pct : Option<Numeric>,
+  #[serde(default, alias = "minFee")]
+  pub min_fee : Option<Numeric>,
+  #[serde(default, alias = "spreadPct")]
+  pub spread_pct : Option<Numeric>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct CreateApiKeyRequest {
+  pub scopes : Vec<Scope>,
+  pub description : Option<String>,
+  // not a flattened ApiKeyLimits, serde ignores aliases on flattened fields
+  #[serde(default, alias = "readLimit")]
+  pub read_limit : Option<u32>,
+  #[serde(default, alias = "tradeLimit")]
+  pub trade_limit : Option<u32>,
+  /// Whether requests made with the key have to be signed
+  #[serde(default)]
+  pub signed : bool
+}
+
+impl CreateApiKeyRequest {
+  pub fn limits(&self) -> ApiKeyLimits {
+    ApiKeyLimits { read_limit : self.read_limit, trade_limit : self.trade_limit }
+  }
+}
+
+#[derive(Deserialize,Clone,Debug)]
+/// Requests a key may make per rate limit window. Limits left out use the `CB_RATE_LIMIT_KEY_*` defaults
+pub struct ApiKeyLimits {
+  #[serde(default, alias = "readLimit")]
+  pub read_limit : Option<u32>,
+  #[serde(default, alias = "tradeLimit")]
+  pub trade_limit : Option<u32>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct SetApiKeySigningRequest {
+  pub signed : bool
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct RotateApiKeyRequest {
+  /// Seconds the old key keeps working, defaults to `CB_API_KEY_ROTATION_GRACE`
+  #[serde(default, alias = "gracePeriodSecs")]
+  pub grace_period_secs : Option<i64>
 }
 
 #[derive(Deserialize,Clone,Debug)]
 pub struct UpdateServerMembersRequest {
+  #[serde(alias = "serverId")]
   pub server_id : String,
+  #[serde(alias = "userIds")]
   pub user_ids : Vec<String>
 }
 
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaderboardSort {
+  Balance,
+  #[default]
+  Networth,
+  Roi
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetLeaderboardRequest {
+  #[serde(alias = "serverId")]
+  pub server_id : String,
+  #[serde(default, alias = "sortBy")]
+  pub sort_by : LeaderboardSort,
+  /// Rank patrons on their wallets scoped to this server rather than their global wallets
+  #[serde(default, alias = "serverWallets")]
+  pub server_wallets : bool,
+  pub limit : Option<i64>,
+  pub offset : Option<i64>
+}
+
 #[derive(Deserialize,Clone,Debug)]
 pub struct GetPortfolioRequest {
-  pub user_id : String
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  /// `average` (default) or `fifo`
+  #[serde(default)]
+  pub accounting : CostBasisMethod,
+  /// Selects the wallet scoped to this server instead of the user's global wallet
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>
 }
 
 #[derive(Deserialize,Clone,Debug)]
+#[serde(from = "CoinIdentifierFields")]
 pub struct CoinIdentifierKey {
   pub crypto_id : Option<String>,
   pub name : Option<String>,
   pub symbol : Option<String>
 }
 
+/// The keys a `CoinIdentifierKey` is read from. serde ignores aliases on structs that are `#[serde(flatten)]`ed into
+/// a request, so `cryptoId` is spelled out as its own field
+#[derive(Deserialize)]
+struct CoinIdentifierFields {
+  crypto_id : Option<String>,
+  #[serde(rename = "cryptoId")]
+  crypto_id_camel : Option<String>,
+  name : Option<String>,
+  symbol : Option<String>
+}
+
+impl From<CoinIdentifierFields> for CoinIdentifierKey {
+  fn from(fields : CoinIdentifierFields) -> CoinIdentifierKey {
+    CoinIdentifierKey { crypto_id : fields.crypto_id.or(fields.crypto_id_camel), name : fields.name, symbol : fields.symbol }
+  }
+}
+
 #[derive(Deserialize,Clone,Debug)]
-/// Describes a requested transaction. Each transaction has a coin key, user id, and a qty of coin to be bought or sold
+/// Describes a requested transaction. Each transaction has a coin key, user id, and either a qty of coin or an amount of
+/// wallet currency to be bought or sold, unless it executes a quote, which already names the coin and qty
 pub struct CoinTransactionRequest {
+  #[serde(alias = "userId")]
   pub user_id : String,
-  pub qty : Numeric,
+  /// Selects the wallet scoped to this server instead of the user's global wallet
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  /// `"all"` sells the whole position
+  pub qty : Option<PositionQty>,
+  /// Trades as much of the coin as this is worth at the execution price, instead of a fixed qty
+  pub amount : Option<Numeric>,
+  /// Trades at the price of a quote from `POST /quotes` instead of the latest price
+  #[serde(default, alias = "quoteId")]
+  pub quote_id : Option<String>,
   /// Allows the request to specify any of the fields in CoinIdentiferKey, and the server will try to resolve the correct coin from the info given if possible
   #[serde(flatten)]
   pub coin_key : CoinIdentifierKey
@@ -51,5 +184,187 @@ pub struct CoinTransactionRequest {
 pub struct CoinTransactionResponse {
   pub msg : String,
   #[serde(skip_serializing_if = "Option::is_none")]
-  pub currencies : Option<Vec<CurrencyData>>
+  pub currencies : Option<Vec<CurrencyData>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub receipt : Option<TradeReceipt>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+/// Places a limit order. Buy orders reserve `qty * limit_price` from the wallet, sell orders reserve `qty` of the coin.
+pub struct PlaceOrderRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  pub side : OrderSide,
+  pub qty : Numeric,
+  #[serde(alias = "limitPrice")]
+  pub limit_price : Numeric,
+  /// Seconds until the order expires if it hasn't filled. Orders without one rest until filled or cancelled.
+  #[serde(default, alias = "expiresIn")]
+  pub expires_in : Option<i64>,
+  #[serde(flatten)]
+  pub coin_key : CoinIdentifierKey
+}
+
+#[derive(Deserialize,Clone,Debug)]
+/// Asks for a price that `/buy` or `/sell` will honour for a short while
+pub struct PlaceQuoteRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  pub side : OrderSide,
+  pub qty : Numeric,
+  #[serde(flatten)]
+  pub coin_key : CoinIdentifierKey
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetOrdersRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  /// Only return orders with this status, e.g. `open`
+  pub status : Option<String>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct CancelOrderRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String
+}
+
+#[derive(Deserialize,Clone,Debug)]
+/// Attaches a stop-loss or take-profit to a position the user holds
+pub struct PlaceTriggerRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  pub kind : TriggerKind,
+  #[serde(alias = "triggerPrice")]
+  pub trigger_price : Numeric,
+  /// How much of the position to sell when the trigger fires, `"all"` sells whatever is held at that time
+  pub qty : PositionQty,
+  #[serde(flatten)]
+  pub coin_key : CoinIdentifierKey
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetTriggersRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  /// Only return triggers with this status, e.g. `active`
+  pub status : Option<String>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetTransactionsRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  #[serde(default, alias = "cryptoId")]
+  pub crypto_id : Option<String>,
+  /// `B`/`buy` or `S`/`sell`
+  #[serde(default)]
+  pub side : Option<OrderSide>,
+  /// Only transactions at or after this time, `%Y-%m-%d %H:%M:%S` UTC
+  #[serde(default, with = "crate::types::optional_date_formatter")]
+  pub from : Option<chrono::DateTime<chrono::Utc>>,
+  /// Only transactions before this time
+  #[serde(default, with = "crate::types::optional_date_formatter")]
+  pub to : Option<chrono::DateTime<chrono::Utc>>,
+  /// `nextCursor` from the previous page
+  pub cursor : Option<i32>,
+  pub limit : Option<i64>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+/// A window of time, given either as a `dateRange` ending now or as explicit `from`/`to` bounds
+pub struct TimeWindowRequest {
+  #[serde(default, alias = "dateRange")]
+  pub date_range : Option<DateRange>,
+  #[serde(default, with = "crate::types::optional_date_formatter")]
+  pub from : Option<chrono::DateTime<chrono::Utc>>,
+  #[serde(default, with = "crate::types::optional_date_formatter")]
+  pub to : Option<chrono::DateTime<chrono::Utc>>
+}
+
+impl TimeWindowRequest {
+  /// Resolves to `(from, to)`. Explicit bounds win over `dateRange`; a missing `to` means now, and a missing `from`
+  /// falls back to the start of `dateRange` (a day when that is missing too).
+  pub fn bounds(&self) -> BrokerResult<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> {
+    let to = self.to.unwrap_or_else(chrono::Utc::now);
+    let from = self.from.unwrap_or_else(|| to - self.date_range.unwrap_or_default().duration());
+    if from >= to {
+      return Err(BrokerError::Validation(String::from("`from` must be before `to`")));
+    }
+    Ok((from, to))
+  }
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetCoinHistoryRequest {
+  #[serde(flatten)]
+  pub coin_key : CoinIdentifierKey,
+  #[serde(flatten)]
+  pub window : TimeWindowRequest,
+  /// Bucket width like `5m` or `1h`, defaults to one that suits the window
+  pub resolution : Option<Interval>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetCandlesRequest {
+  #[serde(flatten)]
+  pub coin_key : CoinIdentifierKey,
+  #[serde(flatten)]
+  pub window : TimeWindowRequest,
+  /// Candle width like `5m`, `1h`, `1d` or `1w`, defaults to one that suits the window
+  pub interval : Option<Interval>
+}
+
+/// Size and colours of a `/graph/*` PNG. Read from the query string separately from the rest of the request
+#[derive(Deserialize,Clone,Debug)]
+pub struct ChartOptions {
+  pub width : Option<u32>,
+  pub height : Option<u32>,
+  /// `light` (default) or `dark`
+  #[serde(default)]
+  pub theme : Theme
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetPerformanceGraphRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetLeaderboardGraphRequest {
+  #[serde(alias = "serverId")]
+  pub server_id : String,
+  /// Compare patrons' wallets scoped to this server rather than their global wallets
+  #[serde(default, alias = "serverWallets")]
+  pub server_wallets : bool,
+  /// How many of the top patrons by net worth to draw
+  pub limit : Option<i64>
+}
+
+#[derive(Deserialize,Clone,Debug)]
+pub struct GetNetWorthHistoryRequest {
+  #[serde(alias = "userId")]
+  pub user_id : String,
+  #[serde(default, alias = "serverId")]
+  pub server_id : Option<String>,
+  #[serde(flatten)]
+  pub window : TimeWindowRequest,
+  /// Keep one snapshot per bucket of this width, like `1h` or `1d`. Defaults to one that suits the window
+  pub resolution : Option<Interval>
 }
@@ -0,0 +1,172 @@
+//! API keys, handed out as `<id>.<secret>` and checked against the salted hashes in `apikeys`
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use crate::ratelimit::RouteClass;
+use crate::types::{ApiKeyRecord, Scope};
+
+/// `NOTIFY` channel `apikeys` announces changes on
+pub const CHANGED_CHANNEL : &str = "apikeys_changed";
+
+/// The key a request was made with, left in the request extensions by `middlewares::apikey`
+#[derive(Clone,Debug)]
+pub struct AuthenticatedKey {
+    /// `apikeys.id`, `None` when a request without a valid key was let through by `CB_AUTH_MODE`
+    pub id : Option<i32>,
+    pub scopes : Vec<Scope>,
+    pub read_limit : Option<u32>,
+    pub trade_limit : Option<u32>,
+    /// Requests made with the key have to be signed, which `middlewares::signature` checks
+    pub signed : bool
+}
+
+impl AuthenticatedKey {
+    /// Stands in for a key when keys aren't being checked, so every route lets the request through
+    pub fn unrestricted() -> AuthenticatedKey {
+        AuthenticatedKey { id : None, scopes : vec![Scope::Admin], read_limit : None, trade_limit : None, signed : false }
+    }
+
+    /// The key's own rate limit for `class`, if it has one
+    pub fn limit(&self, class : RouteClass) -> Option<u32> {
+        match class {
+            RouteClass::Read => self.read_limit,
+            RouteClass::Trade => self.trade_limit
+        }
+    }
+
+    pub fn allows(&self, scope : Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+/// The keys requests are checked against, shared by every worker and swapped out by `jobs::spawn_api_key_refresh`
+#[derive(Clone,Default)]
+pub struct KeyStore {
+    keys : Arc<RwLock<HashMap<i32, ApiKeyRecord>>>,
+    /// When each key was last used since `take_last_used` was called
+    last_used : Arc<Mutex<HashMap<i32, DateTime<Utc>>>>
+}
+
+impl KeyStore {
+    pub fn replace(&self, keys : Vec<ApiKeyRecord>) {
+        let keys = keys.into_iter().map(|key| (key.id, key)).collect();
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+    }
+
+    /// Looks up a `<id>.<secret>` key, or failing that a legacy key sent whole. The secret's hash is compared in
+    /// constant time, so timing only gives away whether the id exists, and how many legacy keys there are
+    pub fn authenticate(&self, key : &str) -> Option<AuthenticatedKey> {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let matches = |record : &ApiKeyRecord, secret : &str| -> bool {
+            hash_secret(&record.salt, secret).as_slice().ct_eq(record.key_hash.as_slice()).into()
+        };
+        let record = match key.split_once('.').and_then(|(id, secret)| Some((keys.get(&id.parse().ok()?)?, secret))) {
+            Some((record, secret)) if !record.legacy && matches(record, secret) => record,
+            _ => keys.values().find(|record| record.legacy && matches(record, key))?
+        };
+        let now = Utc::now();
+        // the store is only reloaded now and then, so rotated keys are expired here rather than by the reload
+        if record.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return None;
+        }
+        self.last_used.lock().unwrap_or_else(|e| e.into_inner()).insert(record.id, now);
+        Some(AuthenticatedKey {
+            id : Some(record.id),
+            scopes : record.scopes.clone(),
+            read_limit : record.read_limit,
+            trade_limit : record.trade_limit,
+            signed : record.signing_secret.is_some()
+        })
+    }
+
+    /// Checks a hex HMAC-SHA256 `signature` of `message` against key `id`'s signing secret, in constant time. The
+    /// secret stays in here rather than travelling with the request
+    pub fn verify_signature(&self, id : i32, message : &[u8], signature : &str) -> bool {
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let (Some(secret), Ok(signature)) = (keys.get(&id).and_then(|key| key.signing_secret.as_ref()), hex::decode(signature)) else {
+            return false;
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(message);
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    /// When each key was last used since the previous call
+    pub fn take_last_used(&self) -> HashMap<i32, DateTime<Utc>> {
+        std::mem::take(&mut *self.last_used.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Same as `hash_api_key` in schema.sql
+fn hash_secret(salt : &[u8], secret : &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(secret.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn record(id : i32, secret : &str, expires_at : Option<DateTime<Utc>>, legacy : bool) -> ApiKeyRecord {
+        let salt = vec![id as u8; 16];
+        ApiKeyRecord {
+            id,
+            key_hash : hash_secret(&salt, secret),
+            salt,
+            scopes : vec![Scope::Read],
+            expires_at,
+            read_limit : Some(5),
+            trade_limit : None,
+            signing_secret : None,
+            legacy
+        }
+    }
+
+    fn store() -> KeyStore {
+        let store = KeyStore::default();
+        store.replace(vec![
+            record(1, "secret", None, false),
+            record(2, "rotated", Some(Utc::now() + Duration::hours(1)), false),
+            record(3, "expired", Some(Utc::now() - Duration::seconds(1)), false),
+            record(4, "old.style-key", None, true)
+        ]);
+        store
+    }
+
+    #[test]
+    fn accepts_the_right_secret() {
+        let store = store();
+        let key = store.authenticate("1.secret").unwrap();
+        assert_eq!(key.id, Some(1));
+        assert_eq!(key.scopes, vec![Scope::Read]);
+        assert_eq!(key.read_limit, Some(5));
+        assert_eq!(store.authenticate("2.rotated").and_then(|key| key.id), Some(2));
+        assert_eq!(store.take_last_used().len(), 2);
+    }
+
+    #[test]
+    fn refuses_wrong_secrets_and_expired_keys() {
+        let store = store();
+        for key in ["1.wrong", "1.", "1.secret ", "2.secret", "3.expired", "5.secret", "x.secret", "secret", "", "4.old.style-key"] {
+            assert!(store.authenticate(key).is_none(), "{} was let in", key);
+        }
+        assert!(store.take_last_used().is_empty());
+    }
+
+    #[test]
+    fn accepts_legacy_keys_whole() {
+        let store = store();
+        assert_eq!(store.authenticate("old.style-key").and_then(|key| key.id), Some(4));
+        // a legacy key's hash doesn't make it a valid `<id>.<secret>` key
+        store.replace(vec![record(4, "secret", None, true)]);
+        assert!(store.authenticate("4.secret").is_none());
+        assert!(store.authenticate("secret").is_some());
+    }
+}
@@ -1,9 +1,23 @@
 use serde::Deserialize;
 use tokio_postgres::{Config as PgConfig};
+use crate::types::{AuthMode, Numeric};
+use std::ops::RangeBounds;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug,Deserialize,Clone)]
 pub struct Config {
-  pub data_source : DataSource
+  pub data_source : DataSource,
+  pub pool : PoolConfig,
+  pub daily_reward : DailyRewardConfig,
+  pub orders : OrderConfig,
+  pub graphs : GraphConfig,
+  pub networth : NetWorthConfig,
+  pub ingest : IngestConfig,
+  pub quotes : QuoteConfig,
+  pub fees : FeeConfig,
+  pub api_keys : ApiKeyConfig,
+  pub rate_limits : RateLimitConfig
 }
 
 #[derive(Debug,Deserialize,Clone)]
@@ -15,15 +29,177 @@ pub struct DataSource {
   pub port: u16
 }
 
+/// Sizing and timeouts for the shared Postgres connection pool
+#[derive(Debug,Deserialize,Clone)]
+pub struct PoolConfig {
+  /// Maximum number of open connections
+  pub max_size : u32,
+  /// Seconds an unused connection is kept open before it is closed
+  pub idle_timeout_secs : u64,
+  /// Seconds a query will wait to borrow a connection before giving up
+  pub checkout_timeout_secs : u64
+}
+
+/// Amounts paid out by `/daily-reward`
+#[derive(Debug,Deserialize,Clone)]
+pub struct DailyRewardConfig {
+  /// Paid for every claim
+  pub base_amount : Numeric,
+  /// Added for each consecutive day claimed before today
+  pub streak_bonus : Numeric,
+  /// Streak length after which the bonus stops growing
+  pub max_streak_bonus_days : i32
+}
+
+#[derive(Debug,Deserialize,Clone)]
+pub struct OrderConfig {
+  /// Seconds between runs of the limit order matcher
+  pub match_interval_secs : u64
+}
+
+/// Rendering of the `/graph/*` PNGs
+#[derive(Debug,Deserialize,Clone)]
+pub struct GraphConfig {
+  /// TrueType font used for titles, axis labels and legends
+  pub font_path : String,
+  /// Seconds clients and proxies may cache a rendered chart
+  pub cache_max_age_secs : u64
+}
+
+#[derive(Debug,Deserialize,Clone)]
+pub struct NetWorthConfig {
+  /// Seconds between net worth snapshots. Each wallet gets at most one snapshot per interval
+  pub snapshot_interval_secs : u64
+}
+
+#[derive(Debug,Deserialize,Clone)]
+pub struct QuoteConfig {
+  /// Oldest a coin's newest price may be, in seconds, before trades against it are refused. 0 turns the check off
+  pub max_age_secs : u64,
+  /// Seconds a quote from `POST /quotes` can be executed for
+  pub ttl_secs : i64,
+  /// Key quote ids are signed with. Every instance needs the same one, so quotes survive restarts and load balancing
+  pub signing_secret : String
+}
+
+impl QuoteConfig {
+  pub fn max_age(&self) -> Option<Duration> {
+    Some(Duration::from_secs(self.max_age_secs)).filter(|age| !age.is_zero())
+  }
+}
+
+/// Trading fees for every wallet, unless its server overrides them through `PUT /fees`
+#[derive(Debug,Deserialize,Clone)]
+pub struct FeeConfig {
+  /// Percent of the value of each trade charged as commission
+  pub commission_pct : Numeric,
+  /// Smallest commission charged on a trade
+  pub min_fee : Numeric,
+  /// Percent between the bid and ask price. Market trades fill half of it away from the `cryptodata` price
+  pub spread_pct : Numeric
+}
+
+#[derive(Debug,Deserialize,Clone)]
+pub struct ApiKeyConfig {
+  /// Whether requests need a valid key. Independent of the build profile, so a debug build checks keys too
+  pub auth_mode : AuthMode,
+  /// Seconds between reloads of `apikeys`. Changes are also picked up as soon as the table `NOTIFY`s about them
+  pub refresh_interval_secs : u64,
+  /// Seconds a rotated key keeps working by default
+  pub rotation_grace_secs : i64,
+  /// Furthest a signed request's timestamp may be from the server's clock, in seconds. Nonces are remembered this long
+  pub signature_max_skew_secs : u64
+}
+
+impl ApiKeyConfig {
+  pub fn refresh_interval(&self) -> Duration {
+    Duration::from_secs(self.refresh_interval_secs)
+  }
+
+  pub fn signature_max_skew(&self) -> Duration {
+    Duration::from_secs(self.signature_max_skew_secs)
+  }
+}
+
+/// Requests allowed per window, each refilling steadily over the window. `GET`s count as reads and everything else as
+/// trades. 0 means no limit
+#[derive(Debug,Deserialize,Clone)]
+pub struct RateLimitConfig {
+  pub window_secs : u64,
+  /// For each API key, unless `apikeys.readLimit` overrides it
+  pub key_read : u32,
+  /// For each API key, unless `apikeys.tradeLimit` overrides it
+  pub key_trade : u32,
+  /// For each `userId`, across every API key
+  pub user_read : u32,
+  pub user_trade : u32
+}
+
+impl RateLimitConfig {
+  pub fn window(&self) -> Duration {
+    Duration::from_secs(self.window_secs)
+  }
+}
+
+/// The optional worker that polls a CoinGecko `/coins/markets` compatible endpoint and writes the quotes to `cryptodata`
+#[derive(Debug,Deserialize,Clone)]
+pub struct IngestConfig {
+  pub enabled : bool,
+  /// API root, e.g. `https://api.coingecko.com/api/v3`. Point it at a mock server to test without CoinGecko
+  pub base_url : String,
+  /// Seconds between polls
+  pub interval_secs : u64,
+  /// Currency prices are quoted in
+  pub vs_currency : String,
+  /// Coins per page, CoinGecko allows up to 250
+  pub per_page : u32,
+  /// Pages fetched per poll, so the top `per_page * pages` coins by market cap are tracked
+  pub pages : u32,
+  /// Longest the worker waits between polls while it is being rate limited
+  pub max_backoff_secs : u64
+}
+
+impl IngestConfig {
+  pub fn interval(&self) -> Duration {
+    Duration::from_secs(self.interval_secs)
+  }
+
+  pub fn max_backoff(&self) -> Duration {
+    Duration::from_secs(self.max_backoff_secs)
+  }
+}
+
+impl NetWorthConfig {
+  pub fn snapshot_interval(&self) -> Duration {
+    Duration::from_secs(self.snapshot_interval_secs)
+  }
+}
+
+impl OrderConfig {
+  pub fn match_interval(&self) -> Duration {
+    Duration::from_secs(self.match_interval_secs)
+  }
+}
+
+impl PoolConfig {
+  pub fn idle_timeout(&self) -> Duration {
+    Duration::from_secs(self.idle_timeout_secs)
+  }
+
+  pub fn checkout_timeout(&self) -> Duration {
+    Duration::from_secs(self.checkout_timeout_secs)
+  }
+}
+
 impl std::fmt::Display for DataSource {
   fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
-    write!(f, "{}", "DataSource { ")?;
+    write!(f, "DataSource {{ ")?;
     write!(f, "username: {}, ", self.username)?;
     write!(f, "password: {}, ", self.password)?;
     write!(f, "schema: {}, ", self.schema)?;
     write!(f, "host: {}, ", self.host)?;
     write!(f, "port: {} ", self.port)?;
-    write!(f, "{}", "}")?;
+    write!(f, "}}")?;
     Ok(())
   }
 }
@@ -36,8 +212,34 @@ impl From<&DataSource> for PgConfig {
        .dbname(ds.schema.as_str())
        .host(ds.host.as_str())
        .port(ds.port);
-    return cfg;
+    cfg
+  }
+}
+
+/// Reads an optional environment variable, falling back to `default` when it is not set.
+fn var_or<T : FromStr>(name : &str, default : T) -> T {
+  match dotenv::var(name) {
+    Ok(s) => s.parse::<T>().unwrap_or_else(|_| panic!("Could not parse `{}` environment variable.", name)),
+    Err(_) => default
+  }
+}
+
+/// Like `var_or`, for intervals that can't be 0
+fn interval_var_or(name : &str, default : u64) -> u64 {
+  let secs = var_or(name, default);
+  if secs == 0 {
+    panic!("`{}` must be at least 1 second.", name);
   }
+  secs
+}
+
+/// Like `var_or`, for values that have to fall in `range`, which `expected` describes
+fn range_var_or<T : FromStr + PartialOrd>(name : &str, default : T, range : impl RangeBounds<T>, expected : &str) -> T {
+  let value = var_or(name, default);
+  if !range.contains(&value) {
+    panic!("`{}` must be {}.", name, expected);
+  }
+  value
 }
 
 pub fn load_config() -> Config {
@@ -48,6 +250,58 @@ pub fn load_config() -> Config {
       schema : dotenv::var("CB_DBSCHEMA").expect("Missing database schema. Try adding `CB_DBSCHEMA` environment variable."),
       port : dotenv::var("CB_DBPORT").expect("Missing database port. Try adding `CB_DBPORT` environment variable.").parse::<u16>().expect("CB_DBPORT must be an unsigned integer 0-65535"),
       host : dotenv::var("CB_DBHOST").expect("Missing database host. Try adding `CB_DBHOST` environment variable.")
+    },
+    pool : PoolConfig {
+      max_size : var_or("CB_DBPOOL_MAX_SIZE", 16),
+      idle_timeout_secs : var_or("CB_DBPOOL_IDLE_TIMEOUT", 600),
+      checkout_timeout_secs : var_or("CB_DBPOOL_CHECKOUT_TIMEOUT", 30)
+    },
+    daily_reward : DailyRewardConfig {
+      base_amount : var_or("CB_DAILY_REWARD", Numeric::from(100)),
+      streak_bonus : var_or("CB_DAILY_STREAK_BONUS", Numeric::from(10)),
+      max_streak_bonus_days : var_or("CB_DAILY_STREAK_MAX_DAYS", 7)
+    },
+    orders : OrderConfig {
+      match_interval_secs : interval_var_or("CB_ORDER_MATCH_INTERVAL", 15)
+    },
+    graphs : GraphConfig {
+      font_path : var_or("CB_GRAPH_FONT", String::from("/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf")),
+      cache_max_age_secs : var_or("CB_GRAPH_CACHE_MAX_AGE", 60)
+    },
+    networth : NetWorthConfig {
+      snapshot_interval_secs : interval_var_or("CB_NETWORTH_SNAPSHOT_INTERVAL", 3600)
+    },
+    ingest : IngestConfig {
+      enabled : var_or("CB_INGEST_ENABLED", false),
+      base_url : var_or("CB_INGEST_BASE_URL", String::from("https://api.coingecko.com/api/v3")),
+      interval_secs : interval_var_or("CB_INGEST_INTERVAL", 60),
+      vs_currency : var_or("CB_INGEST_VS_CURRENCY", String::from("usd")),
+      per_page : range_var_or("CB_INGEST_PER_PAGE", 100, 1.., "at least 1"),
+      pages : range_var_or("CB_INGEST_PAGES", 1, 1.., "at least 1"),
+      max_backoff_secs : interval_var_or("CB_INGEST_MAX_BACKOFF", 900)
+    },
+    quotes : QuoteConfig {
+      max_age_secs : var_or("CB_MAX_QUOTE_AGE", 900),
+      ttl_secs : var_or("CB_QUOTE_TTL", 30),
+      signing_secret : dotenv::var("CB_QUOTE_SECRET").ok().filter(|s| !s.is_empty()).expect("Missing quote signing secret. Try adding `CB_QUOTE_SECRET` environment variable.")
+    },
+    fees : FeeConfig {
+      commission_pct : range_var_or("CB_FEE_COMMISSION_PCT", Numeric::ZERO, Numeric::ZERO..Numeric::ONE_HUNDRED, "0 to under 100"),
+      min_fee : range_var_or("CB_FEE_MIN", Numeric::ZERO, Numeric::ZERO.., "at least 0"),
+      spread_pct : range_var_or("CB_FEE_SPREAD_PCT", Numeric::ZERO, Numeric::ZERO..Numeric::from(200), "0 to under 200")
+    },
+    api_keys : ApiKeyConfig {
+      auth_mode : var_or("CB_AUTH_MODE", AuthMode::Enforce),
+      refresh_interval_secs : interval_var_or("CB_API_KEY_REFRESH_INTERVAL", 300),
+      rotation_grace_secs : var_or("CB_API_KEY_ROTATION_GRACE", 86400),
+      signature_max_skew_secs : var_or("CB_SIGNATURE_MAX_SKEW", 300)
+    },
+    rate_limits : RateLimitConfig {
+      window_secs : var_or("CB_RATE_LIMIT_WINDOW", 60),
+      key_read : var_or("CB_RATE_LIMIT_KEY_READ", 300),
+      key_trade : var_or("CB_RATE_LIMIT_KEY_TRADE", 60),
+      user_read : var_or("CB_RATE_LIMIT_USER_READ", 60),
+      user_trade : var_or("CB_RATE_LIMIT_USER_TRADE", 20)
     }
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,181 @@
+use actix_web::{HttpResponse, ResponseError, http::StatusCode, http::header};
+use serde::Serialize;
+use tokio_postgres::error::SqlState;
+
+pub type BrokerResult<T> = Result<T, BrokerError>;
+
+/// Every failure a handler can report. Each variant maps to one HTTP status and one stable `code` string that
+/// clients can match on without parsing the message.
+#[derive(Debug)]
+pub enum BrokerError {
+    NotFound(String),
+    Validation(String),
+    InsufficientFunds(String),
+    Conflict(String),
+    /// The newest price for a coin is too old to trade at
+    StalePrice(String),
+    /// The quote being executed is past its expiry
+    QuoteExpired(String),
+    /// The quote being executed already has been
+    QuoteUsed(String),
+    /// The action was already taken and can be retried after `retry_after_secs`
+    Cooldown { msg : String, retry_after_secs : i64 },
+    /// Too many requests from an API key or for a user. `limit` and `reset_secs` describe the bucket that ran out
+    RateLimited { msg : String, retry_after_secs : i64, limit : u32, reset_secs : u64 },
+    Unauthorized(String),
+    /// The API key is valid but lacks the scope the route requires
+    Forbidden(String),
+    Internal(String)
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    success : bool,
+    code : &'static str,
+    message : &'a str,
+    #[serde(rename = "retryAfter", skip_serializing_if = "Option::is_none")]
+    retry_after : Option<i64>
+}
+
+impl BrokerError {
+    /// Machine readable error code sent alongside the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            BrokerError::NotFound(_) => "not_found",
+            BrokerError::Validation(_) => "validation_error",
+            BrokerError::InsufficientFunds(_) => "insufficient_funds",
+            BrokerError::Conflict(_) => "conflict",
+            BrokerError::StalePrice(_) => "stale_price",
+            BrokerError::QuoteExpired(_) => "quote_expired",
+            BrokerError::QuoteUsed(_) => "quote_used",
+            BrokerError::Cooldown { .. } => "cooldown",
+            BrokerError::RateLimited { .. } => "rate_limited",
+            BrokerError::Unauthorized(_) => "unauthorized",
+            BrokerError::Forbidden(_) => "forbidden",
+            BrokerError::Internal(_) => "internal_error"
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            BrokerError::NotFound(m) | BrokerError::Validation(m) | BrokerError::InsufficientFunds(m)
+            | BrokerError::Conflict(m) | BrokerError::StalePrice(m) | BrokerError::QuoteExpired(m) | BrokerError::QuoteUsed(m)
+            | BrokerError::Cooldown { msg : m, .. } | BrokerError::RateLimited { msg : m, .. } | BrokerError::Unauthorized(m) | BrokerError::Forbidden(m) | BrokerError::Internal(m) => m
+        }
+    }
+
+    /// The message sent to the client. Internal errors can carry database or library details, so those are only logged
+    fn public_message(&self) -> &str {
+        match self {
+            BrokerError::Internal(_) => "Internal error",
+            _ => self.message()
+        }
+    }
+
+    fn retry_after(&self) -> Option<i64> {
+        match self {
+            BrokerError::Cooldown { retry_after_secs, .. } | BrokerError::RateLimited { retry_after_secs, .. } => Some(*retry_after_secs),
+            _ => None
+        }
+    }
+
+    /// Wraps an error raised by something other than a handler (e.g. another middleware), keeping its status code.
+    pub fn from_status(status : StatusCode, msg : String) -> BrokerError {
+        match status {
+            StatusCode::NOT_FOUND => BrokerError::NotFound(msg),
+            StatusCode::CONFLICT => BrokerError::Conflict(msg),
+            StatusCode::UNAUTHORIZED => BrokerError::Unauthorized(msg),
+            StatusCode::FORBIDDEN => BrokerError::Forbidden(msg),
+            s if s.is_client_error() => BrokerError::Validation(msg),
+            _ => BrokerError::Internal(msg)
+        }
+    }
+}
+
+impl std::fmt::Display for BrokerError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+impl ResponseError for BrokerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            BrokerError::NotFound(_) => StatusCode::NOT_FOUND,
+            BrokerError::Validation(_) => StatusCode::BAD_REQUEST,
+            BrokerError::InsufficientFunds(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            BrokerError::Conflict(_) | BrokerError::Cooldown { .. } | BrokerError::QuoteUsed(_) => StatusCode::CONFLICT,
+            BrokerError::QuoteExpired(_) => StatusCode::GONE,
+            BrokerError::StalePrice(_) => StatusCode::SERVICE_UNAVAILABLE,
+            BrokerError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            BrokerError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            BrokerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            BrokerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if let BrokerError::Internal(msg) = self {
+            log::error!("Internal error: {}", msg);
+        }
+        let mut builder = HttpResponse::build(self.status_code());
+        if let Some(secs) = self.retry_after() {
+            builder.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+        if let BrokerError::RateLimited { limit, reset_secs, .. } = self {
+            builder.insert_header(("X-RateLimit-Limit", limit.to_string()))
+                .insert_header(("X-RateLimit-Remaining", "0"))
+                .insert_header(("X-RateLimit-Reset", reset_secs.to_string()));
+        }
+        builder.json(ErrorBody {
+            success : false,
+            code : self.code(),
+            message : self.public_message(),
+            retry_after : self.retry_after()
+        })
+    }
+}
+
+impl From<tokio_postgres::Error> for BrokerError {
+    fn from(e : tokio_postgres::Error) -> BrokerError {
+        let db_error = match e.as_db_error() {
+            Some(db_error) => db_error,
+            None => return BrokerError::Internal(e.to_string())
+        };
+        let msg = db_error.message().to_string();
+        match db_error.code() {
+            // Raised by the buy_currency / sell_currency procedures in schema.sql
+            c if *c == SqlState::RAISE_EXCEPTION => {
+                if msg.starts_with("Insufficient funds") {
+                    BrokerError::InsufficientFunds(msg)
+                } else if msg.starts_with("Could not find a nonzero price") || msg.starts_with("Could not find quote") {
+                    BrokerError::NotFound(msg)
+                } else if msg.starts_with("Stale price") {
+                    BrokerError::StalePrice(msg)
+                } else if msg.starts_with("Quote expired") {
+                    BrokerError::QuoteExpired(msg)
+                } else if msg.starts_with("Quote already used") {
+                    BrokerError::QuoteUsed(msg)
+                } else {
+                    BrokerError::Validation(msg)
+                }
+            },
+            // the database's own messages name constraints and columns, so these get generic ones
+            c if *c == SqlState::UNIQUE_VIOLATION => BrokerError::Conflict(String::from("That already exists")),
+            c if *c == SqlState::CHECK_VIOLATION || *c == SqlState::NUMERIC_VALUE_OUT_OF_RANGE =>
+                BrokerError::Validation(String::from("A value in the request is out of range")),
+            _ => BrokerError::Internal(e.to_string())
+        }
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for BrokerError {
+    fn from(e : bb8::RunError<tokio_postgres::Error>) -> BrokerError {
+        match e {
+            bb8::RunError::User(e) => e.into(),
+            bb8::RunError::TimedOut => BrokerError::Internal(String::from("Timed out waiting for a database connection"))
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Line charts for the `/graph/*` endpoints, drawn in-process with plotters and encoded as PNG
+
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+use plotters::style::register_font;
+use serde::Deserialize;
+use crate::errors::{BrokerError, BrokerResult};
+
+/// Family every chart asks plotters for. `load_font` registers the configured TrueType file under it
+const FONT_FAMILY : &str = "sans-serif";
+
+pub const MIN_WIDTH : u32 = 200;
+pub const MAX_WIDTH : u32 = 2000;
+pub const MIN_HEIGHT : u32 = 150;
+pub const MAX_HEIGHT : u32 = 1500;
+
+/// Reads the font at `path` and registers it for chart text. It has to live for the rest of the program, so it is leaked.
+pub fn load_font(path : &str) -> Result<(), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Could not read chart font `{}`: {}", path, e))?;
+    register_font(FONT_FAMILY, FontStyle::Normal, Box::leak(bytes.into_boxed_slice()))
+        .map_err(|_| format!("`{}` is not a valid TrueType / OpenType font", path))
+}
+
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark
+}
+
+struct Palette {
+    background : RGBColor,
+    foreground : RGBColor,
+    grid : RGBColor,
+    series : &'static [RGBColor]
+}
+
+const LIGHT_SERIES : [RGBColor; 6] = [
+    RGBColor(31, 119, 180), RGBColor(255, 127, 14), RGBColor(44, 160, 44),
+    RGBColor(214, 39, 40), RGBColor(148, 103, 189), RGBColor(140, 86, 75)
+];
+const DARK_SERIES : [RGBColor; 6] = [
+    RGBColor(88, 166, 255), RGBColor(255, 166, 87), RGBColor(86, 211, 100),
+    RGBColor(248, 81, 73), RGBColor(188, 140, 255), RGBColor(227, 179, 65)
+];
+
+impl Theme {
+    fn palette(&self) -> Palette {
+        match self {
+            Theme::Light => Palette {
+                background : RGBColor(255, 255, 255),
+                foreground : RGBColor(36, 41, 47),
+                grid : RGBColor(230, 232, 235),
+                series : &LIGHT_SERIES
+            },
+            Theme::Dark => Palette {
+                background : RGBColor(32, 34, 37),
+                foreground : RGBColor(220, 221, 222),
+                grid : RGBColor(54, 57, 63),
+                series : &DARK_SERIES
+            }
+        }
+    }
+}
+
+/// One line on a chart. Points are (unix seconds, value) in time order
+pub struct Series {
+    pub label : String,
+    pub points : Vec<(i64, f64)>
+}
+
+pub struct Chart {
+    pub title : String,
+    pub width : u32,
+    pub height : u32,
+    pub theme : Theme
+}
+
+fn draw_error<E : std::fmt::Display>(e : E) -> BrokerError {
+    BrokerError::Internal(format!("Could not draw chart: {}", e))
+}
+
+/// Labels the time axis with as much of the date as the span of the chart needs
+fn format_time(secs : i64, span_secs : i64) -> String {
+    let ts = chrono::NaiveDateTime::from_timestamp(secs, 0);
+    if span_secs <= 2 * 24 * 60 * 60 {
+        ts.format("%H:%M").to_string()
+    } else if span_secs <= 120 * 24 * 60 * 60 {
+        ts.format("%b %d").to_string()
+    } else {
+        ts.format("%Y-%m-%d").to_string()
+    }
+}
+
+fn format_value(value : f64) -> String {
+    let magnitude = value.abs();
+    if magnitude >= 1e9 {
+        format!("{:.1}B", value / 1e9)
+    } else if magnitude >= 1e6 {
+        format!("{:.1}M", value / 1e6)
+    } else if magnitude >= 1e4 {
+        format!("{:.1}k", value / 1e3)
+    } else if magnitude >= 1.0 {
+        format!("{:.2}", value)
+    } else {
+        format!("{:.6}", value)
+    }
+}
+
+/// Draws `series` as lines on one pair of axes and returns the PNG. A legend is added when there is more than one line.
+pub fn render_line_chart(chart : &Chart, series : &[Series]) -> BrokerResult<Vec<u8>> {
+    let mut rgb = vec![0u8; chart.width as usize * chart.height as usize * 3];
+    draw_line_chart(chart, series, &mut rgb)?;
+    encode_png(&rgb, chart.width, chart.height)
+}
+
+fn draw_line_chart(chart : &Chart, series : &[Series], rgb : &mut [u8]) -> BrokerResult<()> {
+    let palette = chart.theme.palette();
+    let root = BitMapBackend::with_buffer(rgb, (chart.width, chart.height)).into_drawing_area();
+    root.fill(&palette.background).map_err(draw_error)?;
+    let mut points = series.iter().flat_map(|s| s.points.iter());
+    let first = match points.next() {
+        Some(p) => *p,
+        None => {
+            let style = (FONT_FAMILY, 18).into_font().color(&palette.foreground).pos(Pos::new(HPos::Center, VPos::Center));
+            root.draw(&Text::new(format!("{}: no data for this period", chart.title), ((chart.width / 2) as i32, (chart.height / 2) as i32), style))
+                .map_err(draw_error)?;
+            return root.present().map_err(draw_error);
+        }
+    };
+    let (mut x_min, mut x_max, mut y_min, mut y_max) = (first.0, first.0, first.1, first.1);
+    for &(x, y) in points {
+        x_min = x_min.min(x);
+        x_max = x_max.max(x);
+        y_min = y_min.min(y);
+        y_max = y_max.max(y);
+    }
+    if x_min == x_max {
+        x_max = x_min + 1;
+    }
+    // keep flat lines off the edges of the plot
+    let padding = if y_max > y_min { (y_max - y_min) * 0.05 } else { y_max.abs().max(1.0) * 0.05 };
+    let span = x_max - x_min;
+
+    let mut plot = ChartBuilder::on(&root)
+        .caption(&chart.title, (FONT_FAMILY, 20).into_font().color(&palette.foreground))
+        .margin(12)
+        .x_label_area_size(30)
+        .y_label_area_size(70)
+        .build_cartesian_2d(x_min..x_max, (y_min - padding)..(y_max + padding))
+        .map_err(draw_error)?;
+    plot.configure_mesh()
+        .x_labels(6)
+        .y_labels(6)
+        .x_label_formatter(&|x| format_time(*x, span))
+        .y_label_formatter(&|y| format_value(*y))
+        .label_style((FONT_FAMILY, 12).into_font().color(&palette.foreground))
+        .axis_style(palette.foreground)
+        .bold_line_style(palette.grid)
+        .light_line_style(palette.background)
+        .draw()
+        .map_err(draw_error)?;
+    for (i, line) in series.iter().enumerate() {
+        let color = palette.series[i % palette.series.len()];
+        plot.draw_series(LineSeries::new(line.points.iter().copied(), color.stroke_width(2)))
+            .map_err(draw_error)?
+            .label(line.label.as_str())
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 16, y)], color.stroke_width(2)));
+    }
+    if series.len() > 1 {
+        plot.configure_series_labels()
+            .position(SeriesLabelPosition::UpperLeft)
+            .label_font((FONT_FAMILY, 12).into_font().color(&palette.foreground))
+            .background_style(palette.background.mix(0.85))
+            .border_style(palette.grid)
+            .draw()
+            .map_err(draw_error)?;
+    }
+    root.present().map_err(draw_error)
+}
+
+fn encode_png(rgb : &[u8], width : u32, height : u32) -> BrokerResult<Vec<u8>> {
+    let mut png_bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_bytes, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(draw_error)?;
+    writer.write_image_data(rgb).map_err(draw_error)?;
+    writer.finish().map_err(draw_error)?;
+    Ok(png_bytes)
+}
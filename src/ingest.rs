@@ -0,0 +1,173 @@
+//! Client for a CoinGecko `/coins/markets` compatible endpoint, used by the price ingestion job to fill `cryptodata`
+
+use std::time::Duration;
+use serde::Deserialize;
+use crate::config::IngestConfig;
+use crate::types::Numeric;
+
+/// One coin from `/coins/markets`. CoinGecko sends null for figures it doesn't have yet
+#[derive(Deserialize,Clone,Debug)]
+pub struct MarketQuote {
+    pub id : String,
+    pub symbol : String,
+    pub name : String,
+    pub image : Option<String>,
+    pub current_price : Option<Numeric>,
+    pub market_cap : Option<Numeric>,
+    pub total_volume : Option<Numeric>,
+    /// ISO 8601 time CoinGecko last updated the coin, stored as-is in `coingecko_timestamp`
+    pub last_updated : Option<String>
+}
+
+pub enum FetchError {
+    /// HTTP 429. Holds the server's `Retry-After` when it sent one
+    RateLimited(Option<Duration>),
+    Other(String)
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FetchError::RateLimited(_) => write!(f, "Rate limited by the price feed"),
+            FetchError::Other(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e : reqwest::Error) -> FetchError {
+        FetchError::Other(e.to_string())
+    }
+}
+
+#[derive(Clone)]
+pub struct MarketClient {
+    http : reqwest::Client,
+    config : IngestConfig
+}
+
+impl MarketClient {
+    pub fn new(config : IngestConfig) -> MarketClient {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .user_agent(concat!("crypto-broker-rest-api/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Could not build the price feed HTTP client.");
+        MarketClient { http, config }
+    }
+
+    /// Fetches every configured page of coins, ordered by market cap
+    pub async fn fetch_markets(&self) -> Result<Vec<MarketQuote>, FetchError> {
+        let url = format!("{}/coins/markets", self.config.base_url.trim_end_matches('/'));
+        let mut quotes = Vec::new();
+        for page in 1..=self.config.pages {
+            let response = self.http.get(&url)
+                .query(&[
+                    ("vs_currency", self.config.vs_currency.clone()),
+                    ("order", String::from("market_cap_desc")),
+                    ("per_page", self.config.per_page.to_string()),
+                    ("page", page.to_string())
+                ])
+                .send()
+                .await?;
+            if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = response.headers().get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs);
+                return Err(FetchError::RateLimited(retry_after));
+            }
+            let page_quotes : Vec<MarketQuote> = response.error_for_status()?.json().await?;
+            let last_page = (page_quotes.len() as u32) < self.config.per_page;
+            quotes.extend(page_quotes);
+            if last_page {
+                break;
+            }
+        }
+        Ok(quotes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    fn config(base_url : String, per_page : u32, pages : u32) -> IngestConfig {
+        IngestConfig {
+            enabled : true,
+            base_url,
+            interval_secs : 60,
+            vs_currency : String::from("usd"),
+            per_page,
+            pages,
+            max_backoff_secs : 900
+        }
+    }
+
+    fn response(status : &str, headers : &str, body : &str) -> String {
+        format!("HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, headers, body.len(), body)
+    }
+
+    fn coin(id : &str) -> String {
+        format!(r#"{{"id":"{0}","symbol":"{0}","name":"{0}","image":null,"current_price":1.5,"market_cap":null,"total_volume":null,"last_updated":"2021-01-01T00:00:00.000Z"}}"#, id)
+    }
+
+    /// Stands in for CoinGecko: answers one connection per response, in order, and hands back the request lines
+    async fn mock_feed(responses : Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/v3/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..read]);
+                }
+                requests.push(String::from_utf8_lossy(&request).lines().next().unwrap_or_default().to_string());
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        (base_url, server)
+    }
+
+    #[tokio::test]
+    async fn fetches_pages_until_a_short_one() {
+        let (base_url, server) = mock_feed(vec![
+            response("200 OK", "", &format!("[{},{}]", coin("bitcoin"), coin("ethereum"))),
+            response("200 OK", "", &format!("[{}]", coin("tether")))
+        ]).await;
+        let quotes = MarketClient::new(config(base_url, 2, 5)).fetch_markets().await.ok().unwrap();
+        let ids : Vec<&str> = quotes.iter().map(|q| q.id.as_str()).collect();
+        assert_eq!(ids, ["bitcoin", "ethereum", "tether"]);
+        assert_eq!(quotes[0].current_price, Some(Numeric::new(15, 1)));
+        assert_eq!(quotes[0].image, None);
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("GET /api/v3/coins/markets?vs_currency=usd&order=market_cap_desc&per_page=2&page=1 "));
+        assert!(requests[1].contains("&page=2 "));
+    }
+
+    #[tokio::test]
+    async fn passes_on_retry_after() {
+        let (base_url, server) = mock_feed(vec![
+            response("429 Too Many Requests", "Retry-After: 120\r\n", "{}"),
+            response("429 Too Many Requests", "", "{}")
+        ]).await;
+        let client = MarketClient::new(config(base_url, 2, 1));
+        assert!(matches!(client.fetch_markets().await, Err(FetchError::RateLimited(Some(d))) if d == Duration::from_secs(120)));
+        assert!(matches!(client.fetch_markets().await, Err(FetchError::RateLimited(None))));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! Background tasks that run alongside the HTTP server for as long as it is up
+
+use std::sync::Arc;
+use std::time::Duration;
+use log::{info, warn, error};
+use tokio::sync::Notify;
+use crate::apikeys::{KeyStore, CHANGED_CHANNEL};
+use crate::config::IngestConfig;
+use crate::ingest::{FetchError, MarketClient};
+use crate::persistence::BrokerMapper;
+
+/// Every `every`: fills resting limit orders against new `cryptodata` rows (expiring old ones), then fires any
+/// stop-loss / take-profit triggers the latest prices crossed.
+pub fn spawn_order_matcher(broker_mapper : BrokerMapper, every : Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match broker_mapper.match_limit_orders().await {
+                Ok(0) => {},
+                Ok(filled) => info!("Filled {} limit orders", filled),
+                Err(e) => error!("Limit order matching failed: {}", e)
+            }
+            match broker_mapper.fire_price_triggers().await {
+                Ok(0) => {},
+                Ok(fired) => info!("Fired {} price triggers", fired),
+                Err(e) => error!("Price trigger check failed: {}", e)
+            }
+        }
+    });
+}
+
+/// Every `every`: snapshots each wallet's net worth. Snapshots are keyed on the interval they fall in, so a restart
+/// part way through an interval doesn't write a second one.
+pub fn spawn_networth_snapshots(broker_mapper : BrokerMapper, every : Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match broker_mapper.snapshot_networth(every).await {
+                Ok(0) => {},
+                Ok(written) => info!("Snapshotted the net worth of {} wallets", written),
+                Err(e) => error!("Net worth snapshot failed: {}", e)
+            }
+        }
+    });
+}
+
+/// Polls the price feed every `config.interval()` and writes what it returns to `cryptodata`. While the feed answers
+/// 429 the wait doubles (or follows its `Retry-After`), up to `config.max_backoff()` either way.
+pub fn spawn_price_ingestion(broker_mapper : BrokerMapper, config : IngestConfig) {
+    let client = MarketClient::new(config.clone());
+    tokio::spawn(async move {
+        let mut backoff : Option<Duration> = None;
+        loop {
+            let wait = match client.fetch_markets().await {
+                Ok(quotes) => {
+                    backoff = None;
+                    match broker_mapper.insert_market_quotes(&quotes).await {
+                        Ok(inserted) => info!("Ingested {} prices", inserted),
+                        Err(e) => error!("Storing ingested prices failed: {}", e)
+                    }
+                    config.interval()
+                },
+                Err(FetchError::RateLimited(retry_after)) => {
+                    let (next, wait) = rate_limited_wait(backoff, retry_after, &config);
+                    backoff = Some(next);
+                    warn!("Price feed is rate limiting us, polling again in {}s", wait.as_secs());
+                    wait
+                },
+                Err(e) => {
+                    error!("Fetching prices failed: {}", e);
+                    config.interval()
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+/// The backoff after another 429, and how long to wait before the next poll. The feed's `Retry-After` is followed,
+/// but never past `config.max_backoff()`
+fn rate_limited_wait(backoff : Option<Duration>, retry_after : Option<Duration>, config : &IngestConfig) -> (Duration, Duration) {
+    let next = backoff.map_or(config.interval() * 2, |b| b * 2).min(config.max_backoff());
+    (next, retry_after.unwrap_or(next).min(config.max_backoff()))
+}
+
+/// Reloads `key_store` from `apikeys` every `every`, and whenever the table `NOTIFY`s that it changed. The `LISTEN`
+/// connection is reopened after `every` if it drops, with a reload in case a change was missed in between. Each reload
+/// first writes when the keys were last used back to `apikeys`.
+pub fn spawn_api_key_refresh(broker_mapper : BrokerMapper, key_store : KeyStore, every : Duration) {
+    let changed = Arc::new(Notify::new());
+    let listener = broker_mapper.clone();
+    let notify = changed.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listener.listen(CHANGED_CHANNEL, notify.clone()).await {
+                warn!("Lost the API key change listener, reconnecting in {}s: {}", every.as_secs(), e);
+            }
+            tokio::time::sleep(every).await;
+            notify.notify_one();
+        }
+    });
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        // the first tick is immediate, and the keys were just loaded at startup
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = changed.notified() => {}
+            }
+            let last_used = key_store.take_last_used();
+            if let Err(e) = broker_mapper.record_api_key_use(&last_used).await {
+                error!("Recording when API keys were last used failed: {}", e);
+            }
+            match broker_mapper.api_keys().await {
+                Ok(keys) => key_store.replace(keys),
+                Err(e) => error!("Reloading API keys failed: {}", e)
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limited_wait_doubles_and_caps() {
+        let config = IngestConfig {
+            enabled : true,
+            base_url : String::new(),
+            interval_secs : 60,
+            vs_currency : String::from("usd"),
+            per_page : 100,
+            pages : 1,
+            max_backoff_secs : 300
+        };
+        let secs = Duration::from_secs;
+        for (backoff, retry_after, expected) in [
+            (None, None, (secs(120), secs(120))),
+            (Some(secs(120)), None, (secs(240), secs(240))),
+            (Some(secs(240)), None, (secs(300), secs(300))),
+            (None, Some(secs(10)), (secs(120), secs(10))),
+            (Some(secs(120)), Some(secs(86400)), (secs(240), secs(300)))
+        ] {
+            assert_eq!(rate_limited_wait(backoff, retry_after, &config), expected, "{:?} {:?}", backoff, retry_after);
+        }
+    }
+}
@@ -1,30 +1,29 @@
-use actix_web::{App, HttpServer, middleware::Logger};
+use actix_web::{App, HttpServer, middleware::Logger, web};
 use dotenv::dotenv;
 use env_logger::{init_from_env as init_logger_from_env,Env};
 
 use types::*;
 use persistence::BrokerMapper;
 use config::load_config;
+use apikeys::{AuthenticatedKey, KeyStore};
 
 mod config;
+pub mod errors;
 pub mod types;
 pub mod persistence;
 mod api;
 mod middlewares;
+mod jobs;
+mod accounting;
+mod graphs;
+mod ingest;
+mod quotes;
+mod apikeys;
+mod ratelimit;
+mod signing;
 
-// Validates API keys when in release build
-#[cfg(not(debug_assertions))]
-fn api_key_validatorer(api_keys : Vec<String>) -> impl FnMut(Option<&str>) -> bool {
-    // Safe to use unwrap_or, since at the point contains is evaluated we've already determined 
-    // we have a value. So "" should never happen, but we cannot just use unwrap() since that
-    // will be evaluated before the condition and will cause a panic if there isn't an API key.
-    move |s : Option<&str>| s.is_some() && api_keys.contains(&s.unwrap_or("").to_string())
-}
-
-// Ignores API key in debug build
-#[cfg(debug_assertions)]
-fn api_key_validatorer(_ : Vec<String>) -> impl FnMut(Option<&str>) -> bool {
-    |_| true
+fn api_key_validatorer(key_store : KeyStore) -> impl Fn(Option<&str>) -> Option<AuthenticatedKey> {
+    move |s : Option<&str>| s.and_then(|key| key_store.authenticate(key))
 }
 
 #[actix_web::main]
@@ -33,21 +32,74 @@ async fn main() -> std::io::Result<()>{
     dotenv().ok();
 
     let config = load_config();
-    let broker_config = config.data_source.clone();
-    let api_keys = BrokerMapper::new(&config.data_source).api_keys().await.expect("Unable to load API keys.");
-    #[allow(deprecated)]
+    // graphs are optional, so a missing font only breaks /graph/* rather than the whole API
+    if let Err(e) = graphs::load_font(&config.graphs.font_path) {
+        log::warn!("{}. Set `CB_GRAPH_FONT` to a TrueType font to enable /graph/* endpoints.", e);
+    }
+    let broker_mapper = BrokerMapper::new(&config.data_source, &config.pool, &config.quotes, &config.fees);
+    let key_store = KeyStore::default();
+    key_store.replace(broker_mapper.api_keys().await.expect("Unable to load API keys."));
+    jobs::spawn_api_key_refresh(broker_mapper.clone(), key_store.clone(), config.api_keys.refresh_interval());
+    jobs::spawn_order_matcher(broker_mapper.clone(), config.orders.match_interval());
+    jobs::spawn_networth_snapshots(broker_mapper.clone(), config.networth.snapshot_interval());
+    if config.ingest.enabled {
+        jobs::spawn_price_ingestion(broker_mapper.clone(), config.ingest.clone());
+    }
+    let auth_mode = config.api_keys.auth_mode;
+    match auth_mode {
+        AuthMode::Enforce => log::info!("API keys are enforced."),
+        AuthMode::LogOnly => log::warn!("`CB_AUTH_MODE` is log-only: requests without a valid API key are let through and logged."),
+        AuthMode::Disabled => {
+            log::warn!("****************************************************************************");
+            log::warn!("`CB_AUTH_MODE` is disabled: API KEYS ARE NOT CHECKED, ANYONE CAN ACT AS ADMIN.");
+            log::warn!("Set `CB_AUTH_MODE=enforce` before exposing this server.");
+            log::warn!("****************************************************************************");
+        }
+    }
+    let quote_signer = quotes::QuoteSigner::new(&config.quotes.signing_secret);
+    let rate_limiter = ratelimit::RateLimiter::new(config.rate_limits.clone());
+    let nonces = signing::NonceCache::new(config.api_keys.signature_max_skew());
+    let state = web::Data::new(RootAppState{ broker_mapper, config, quote_signer });
     HttpServer::new(move || 
         App::new()
-            .data(RootAppState{ broker_mapper: BrokerMapper::new(&broker_config) })
-            .wrap(middlewares::apikey::ApiKeyService::from_validator(api_key_validatorer(api_keys.clone())))
+            .app_data(state.clone())
+            .wrap(middlewares::ratelimit::RateLimitService::new(rate_limiter.clone()))
+            .wrap(middlewares::signature::SignatureService::new(key_store.clone(), nonces.clone()))
+            .wrap(middlewares::apikey::ApiKeyService::from_validator(auth_mode, api_key_validatorer(key_store.clone())))
             .wrap(Logger::default())
             .wrap(middlewares::error::ErrorHandlerService)
+            .wrap(middlewares::deprecation::DeprecationService)
+            .service(api::routes::status)
             .service(api::routes::list)
             .service(api::routes::balance)
             .service(api::routes::daily_reward)
             .service(api::routes::update_server_members)
+            .service(api::routes::leaderboard)
+            .service(api::routes::get_fees)
+            .service(api::routes::update_fees)
+            .service(api::routes::list_api_keys)
+            .service(api::routes::create_api_key)
+            .service(api::routes::rotate_api_key)
+            .service(api::routes::set_api_key_limits)
+            .service(api::routes::set_api_key_signing)
+            .service(api::routes::revoke_api_key)
+            .service(api::routes::place_order)
+            .service(api::routes::list_orders)
+            .service(api::routes::cancel_order)
+            .service(api::routes::place_trigger)
+            .service(api::routes::list_triggers)
+            .service(api::routes::cancel_trigger)
+            .service(api::routes::list_transactions)
             .service(api::routes::get_coin)
+            .service(api::routes::coin_history)
+            .service(api::routes::coin_candles)
+            .service(api::routes::coin_graph)
+            .service(api::routes::performance_graph)
+            .service(api::routes::leaderboard_graph)
+            .service(api::routes::networth_history)
+            .service(api::routes::place_quote)
             .service(api::routes::buy_currency)
+            .service(api::routes::sell_currency)
             .service(api::routes::get_portfolio)
     )
     .bind("0.0.0.0:8080")?
@@ -1,11 +1,15 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::rc::Rc;
-use std::cell::RefCell;
 
-use actix_web::{Error, http::HeaderName, ResponseError, http::StatusCode, http::header::ToStrError};
+use actix_web::{Error, HttpMessage, http::HeaderName, ResponseError, http::StatusCode, http::header::ToStrError};
 use actix_web::dev::{ServiceRequest, ServiceResponse, Service, Transform};
 use std::future::{Ready, Future, ready};
+use log::warn;
+
+use crate::apikeys::AuthenticatedKey;
+use crate::errors::BrokerError;
+use crate::types::{AuthMode, Scope};
 
 const API_KEY_HEADER_NAME : &[u8] = b"X-CB-API-KEY";
 
@@ -36,14 +40,19 @@ impl ResponseError for ApiKeyError {
 // 2. Middleware's call method gets called with normal request.
 
 
-pub struct ApiKeyService<F> where F : FnMut(Option<&str>) -> bool {
-    validator : Rc<RefCell<F>>
+/// Checks the API key header with a validator, which returns the key it belongs to. The key and `mode` are left in the
+/// request extensions for `RequireScope`. Unless `mode` is `Enforce`, requests without a valid key go through with
+/// `AuthenticatedKey::unrestricted`
+pub struct ApiKeyService<F> where F : Fn(Option<&str>) -> Option<AuthenticatedKey> {
+    mode : AuthMode,
+    validator : Rc<F>
 }
 
-impl<F> ApiKeyService<F> where F : FnMut(Option<&str>) -> bool {
-    pub fn from_validator(f : F) -> ApiKeyService<F> {
+impl<F> ApiKeyService<F> where F : Fn(Option<&str>) -> Option<AuthenticatedKey> {
+    pub fn from_validator(mode : AuthMode, f : F) -> ApiKeyService<F> {
         ApiKeyService {
-            validator : Rc::new(RefCell::from(f))
+            mode,
+            validator : Rc::new(f)
         }
     }
 }
@@ -56,7 +65,7 @@ where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S::Future: 'static,
     B: 'static,
-    F: FnMut(Option<&str>) -> bool
+    F: Fn(Option<&str>) -> Option<AuthenticatedKey>
 {
     type Response = ServiceResponse<B>;
     type Error = Error;
@@ -66,13 +75,14 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         let new_validator = self.validator.clone();
-        ready(Ok(ApiKeyMiddleware { service, validator: new_validator }))
+        ready(Ok(ApiKeyMiddleware { service, mode: self.mode, validator: new_validator }))
     }
 }
 
-pub struct ApiKeyMiddleware<S, F> where F : FnMut(Option<&str>) -> bool {
+pub struct ApiKeyMiddleware<S, F> where F : Fn(Option<&str>) -> Option<AuthenticatedKey> {
     service: S,
-    validator : Rc<RefCell<F>>
+    mode : AuthMode,
+    validator : Rc<F>
 }
 
 impl<S, B, F> Service<ServiceRequest> for ApiKeyMiddleware<S, F>
@@ -80,7 +90,7 @@ where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S::Future: 'static,
     B: 'static,
-    F: FnMut(Option<&str>) -> bool
+    F: Fn(Option<&str>) -> Option<AuthenticatedKey>
 {
     type Response = ServiceResponse<B>;
     type Error = Error;
@@ -93,13 +103,21 @@ where
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let api_key_hv_opt = req.headers().get(HeaderName::from_bytes(API_KEY_HEADER_NAME).unwrap());
         let api_key_opt_result : Result<Option<&str>, ToStrError> = api_key_hv_opt.map(|api_key_hv| api_key_hv.to_str()).transpose();
-        if api_key_opt_result.is_err() {
-            return Box::pin(ready(Err(ApiKeyError::InvalidEncoding.into())));
-        }
-        let api_key_opt = api_key_opt_result.unwrap();
-        if !self.validator.borrow_mut()(api_key_opt) {
-            return Box::pin(ready(Err(ApiKeyError::Invalid.into())));
-        }
+        let key_result = match api_key_opt_result {
+            Ok(api_key_opt) => (self.validator)(api_key_opt).ok_or(ApiKeyError::Invalid),
+            Err(_) => Err(ApiKeyError::InvalidEncoding)
+        };
+        let key = match (key_result, self.mode) {
+            (Ok(key), _) => key,
+            (Err(e), AuthMode::Enforce) => return Box::pin(ready(Err(e.into()))),
+            (Err(_), AuthMode::Disabled) => AuthenticatedKey::unrestricted(),
+            (Err(e), AuthMode::LogOnly) => {
+                warn!("{} {} would have been refused: {}", req.method(), req.path(), e);
+                AuthenticatedKey::unrestricted()
+            }
+        };
+        req.extensions_mut().insert(key);
+        req.extensions_mut().insert(self.mode);
 
         let fut = self.service.call(req);
 
@@ -108,4 +126,65 @@ where
             Ok(res)
         })
     }
-}
\ No newline at end of file
+}
+
+/// Refuses requests whose API key doesn't have a scope. Routes declare the scope they need with
+/// `#[get("/path", wrap = "RequireScope::READ")]`
+#[derive(Clone,Copy)]
+pub struct RequireScope(pub Scope);
+
+impl RequireScope {
+    pub const READ : RequireScope = RequireScope(Scope::Read);
+    pub const TRADE : RequireScope = RequireScope(Scope::Trade);
+    pub const ADMIN : RequireScope = RequireScope(Scope::Admin);
+    pub const LEADERBOARD_WRITE : RequireScope = RequireScope(Scope::LeaderboardWrite);
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireScopeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware { service, scope: self.0 }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    service: S,
+    scope: Scope
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let allowed = req.extensions().get::<AuthenticatedKey>().is_some_and(|key| key.allows(self.scope));
+        if !allowed {
+            let msg = format!("This API key doesn't have the `{}` scope", self.scope.as_str());
+            if req.extensions().get::<AuthMode>() != Some(&AuthMode::LogOnly) {
+                return Box::pin(ready(Err(BrokerError::Forbidden(msg).into())));
+            }
+            warn!("{} {} would have been refused: {}", req.method(), req.path(), msg);
+        }
+        Box::pin(self.service.call(req))
+    }
+}
@@ -0,0 +1,62 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::{Error, http::HeaderName, http::HeaderValue};
+use actix_web::dev::{ServiceRequest, ServiceResponse, Service, Transform};
+use std::future::{Ready, Future, ready};
+
+use crate::api::extractors::QueryForm;
+
+/// When the query string form of the mutating endpoints was deprecated, as an RFC 9745 `@<unix time>` date
+const QUERY_FORM_DEPRECATED_AT : &str = "@1792281600";
+
+/// Adds a `Deprecation` header to responses for requests whose body was read from the query string instead of JSON
+pub struct DeprecationService;
+
+impl<S, B> Transform<S, ServiceRequest> for DeprecationService
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DeprecationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DeprecationMiddleware { service }))
+    }
+}
+
+pub struct DeprecationMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for DeprecationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if res.request().extensions().get::<QueryForm>().is_some() {
+                res.headers_mut().insert(HeaderName::from_static("deprecation"), HeaderValue::from_static(QUERY_FORM_DEPRECATED_AT));
+            }
+            Ok(res)
+        })
+    }
+}
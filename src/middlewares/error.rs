@@ -1,35 +1,11 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use actix_web::{Error, ResponseError, http::StatusCode};
+use actix_web::Error;
 use actix_web::dev::{ServiceRequest, ServiceResponse, Service, Transform};
 use std::future::{Ready, Future, ready};
 
-
-#[derive(Debug)]
-enum ErrorHandlerWrappedError {
-    Message(String)
-}
-
-impl std::fmt::Display for ErrorHandlerWrappedError {
-    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
-        match &self {
-            ErrorHandlerWrappedError::Message(s) => {
-                let clean_str = s.replace("\\", "\\\\").replace("\"", "\\\"");
-                write!(f, "{}", "{\"success\":false,\"message\":\"")?;
-                write!(f, "{}", clean_str)?;
-                write!(f, "{}", "\"}")?;
-                Ok(())
-            }
-        }
-    }
-}
-
-impl ResponseError for ErrorHandlerWrappedError {
-    fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
-    }
-}
+use crate::errors::BrokerError;
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
@@ -82,7 +58,9 @@ where
             let response_result = fut.await;
             match response_result {
                 Ok(response) => Ok(response),
-                Err(err) => Err(ErrorHandlerWrappedError::Message(format!("{}", err)).into())
+                // Errors already raised as a BrokerError keep their code, anything else is wrapped with its status preserved
+                Err(err) if err.as_error::<BrokerError>().is_some() => Err(err),
+                Err(err) => Err(BrokerError::from_status(err.as_response_error().status_code(), format!("{}", err)).into())
             }
         })
     }
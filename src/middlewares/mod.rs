@@ -1,2 +1,17 @@
+use actix_web::{Error, FromRequest, web};
+use actix_web::dev::{ServiceRequest, Payload, PayloadStream};
+
 pub mod apikey;
-pub mod error;
\ No newline at end of file
+pub mod deprecation;
+pub mod error;
+pub mod ratelimit;
+pub mod signature;
+
+/// Reads the whole body of `req`, handing it back to the request so the handler can read it again
+async fn read_body(req : ServiceRequest) -> Result<(ServiceRequest, web::Bytes), Error> {
+    let (http_req, mut payload) = req.into_parts();
+    let body = web::Bytes::from_request(&http_req, &mut payload).await?;
+    let replay = body.clone();
+    let stream : PayloadStream = Box::pin(futures_util::stream::once(async move { Ok(replay) }));
+    Ok((ServiceRequest::from_parts(http_req, Payload::from(stream)), body))
+}
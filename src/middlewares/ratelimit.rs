@@ -0,0 +1,164 @@
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::{Error, HttpMessage, web, http::Method, http::HeaderValue};
+use actix_web::dev::{ServiceRequest, ServiceResponse, Service, Transform};
+use serde::Deserialize;
+use std::future::{Ready, Future, ready};
+
+use crate::api::extractors::is_json;
+use crate::apikeys::AuthenticatedKey;
+use crate::errors::BrokerError;
+use crate::ratelimit::{RateLimiter, RouteClass, RateLimitStatus};
+use super::read_body;
+
+/// The part of a request the per-user limits are keyed on
+#[derive(Deserialize)]
+struct UserKey {
+    #[serde(default, alias = "userId")]
+    user_id : Option<String>
+}
+
+/// Turns away requests once their API key or their `userId` has used up its budget, with a 429 and `Retry-After`.
+/// Has to be wrapped inside `ApiKeyService`, which tells it the key
+pub struct RateLimitService {
+    limiter : RateLimiter
+}
+
+impl RateLimitService {
+    pub fn new(limiter : RateLimiter) -> RateLimitService {
+        RateLimitService { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimitService
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), limiter: self.limiter.clone() }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let class = if matches!(*req.method(), Method::GET | Method::HEAD) { RouteClass::Read } else { RouteClass::Trade };
+            let key = req.extensions().get::<AuthenticatedKey>().and_then(|key| key.id.map(|id| (id, key.limit(class))));
+            let (req, user_id) = read_user_id(req, class).await?;
+            let status = limiter.check(class, key, user_id.as_deref()).map_err(|throttled| BrokerError::RateLimited {
+                msg : throttled.msg,
+                retry_after_secs : throttled.retry_after_secs as i64,
+                limit : throttled.limit,
+                reset_secs : throttled.reset_secs
+            })?;
+            let mut res = service.call(req).await?;
+            if let Some(status) = status {
+                add_headers(&mut res, &status);
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Finds the `userId` the handler will act for: from a JSON body, like `JsonOrQuery` and `web::Json` read it, and from
+/// the query string otherwise. `GET`s always take theirs from the query string
+async fn read_user_id(req : ServiceRequest, class : RouteClass) -> Result<(ServiceRequest, Option<String>), Error> {
+    if class == RouteClass::Read || !is_json(&req) {
+        let user_id = web::Query::<UserKey>::from_query(req.query_string()).ok().and_then(|key| key.into_inner().user_id);
+        return Ok((req, user_id));
+    }
+    let (req, body) = read_body(req).await?;
+    let user_id = serde_json::from_slice::<UserKey>(&body).ok().and_then(|key| key.user_id);
+    Ok((req, user_id))
+}
+
+fn add_headers<B>(res : &mut ServiceResponse<B>, status : &RateLimitStatus) {
+    let headers = res.headers_mut();
+    for (name, value) in [
+        ("x-ratelimit-limit", status.limit as u64),
+        ("x-ratelimit-remaining", status.remaining as u64),
+        ("x-ratelimit-reset", status.reset_secs)
+    ] {
+        headers.insert(actix_web::http::HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App, http::StatusCode};
+    use crate::config::RateLimitConfig;
+
+    /// Sends `req` through `RateLimitService`
+    async fn send(limiter : &RateLimiter, req : test::TestRequest) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .default_service(web::route().to(|| async { "ok" }))
+                .wrap(RateLimitService::new(limiter.clone()))
+        ).await;
+        match app.call(req.to_request()).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code()
+        }
+    }
+
+    /// Each user gets one trade and five reads per window, and keys aren't limited
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig { window_secs : 60, key_read : 0, key_trade : 0, user_read : 5, user_trade : 1 })
+    }
+
+    #[tokio::test]
+    async fn charges_the_json_bodys_user() {
+        let limiter = limiter();
+        let trade = |query_user : &str| test::TestRequest::post()
+            .uri(&format!("/buy?userId={}", query_user))
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"userId":"u1","cryptoId":"bitcoin","qty":1}"#);
+        assert_eq!(send(&limiter, trade("u1")).await, StatusCode::OK);
+        // the handler trades for the body's user, so a different `userId` in the query doesn't buy another budget
+        assert_eq!(send(&limiter, trade("someone-else")).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn charges_the_query_strings_user_otherwise() {
+        let limiter = limiter();
+        let trade = |user : &str| test::TestRequest::post().uri(&format!("/buy?userId={}", user));
+        assert_eq!(send(&limiter, trade("u1")).await, StatusCode::OK);
+        assert_eq!(send(&limiter, trade("u1")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(&limiter, trade("u2")).await, StatusCode::OK);
+        // reads have their own budget, and always name the user in the query string
+        let read = test::TestRequest::get().uri("/balance?userId=u1").insert_header(("content-type", "application/json"));
+        assert_eq!(send(&limiter, read).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,227 @@
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::{Error, HttpMessage};
+use actix_web::dev::{ServiceRequest, ServiceResponse, Service, Transform};
+use log::warn;
+use std::future::{Ready, Future, ready};
+
+use crate::apikeys::{AuthenticatedKey, KeyStore};
+use crate::errors::BrokerError;
+use crate::signing::{self, NonceCache};
+use crate::types::AuthMode;
+use super::read_body;
+
+/// Checks the signature of requests made with signed keys. Has to be wrapped inside `ApiKeyService`, which tells it
+/// the key and `AuthMode`. Requests with plain keys go straight through
+pub struct SignatureService {
+    key_store : KeyStore,
+    nonces : NonceCache
+}
+
+impl SignatureService {
+    pub fn new(key_store : KeyStore, nonces : NonceCache) -> SignatureService {
+        SignatureService { key_store, nonces }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SignatureService
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SignatureMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SignatureMiddleware { service: Rc::new(service), key_store: self.key_store.clone(), nonces: self.nonces.clone() }))
+    }
+}
+
+pub struct SignatureMiddleware<S> {
+    service: Rc<S>,
+    key_store: KeyStore,
+    nonces: NonceCache
+}
+
+impl<S, B> Service<ServiceRequest> for SignatureMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let key_id = req.extensions().get::<AuthenticatedKey>().filter(|key| key.signed).and_then(|key| key.id);
+        let mode = req.extensions().get::<AuthMode>().copied().unwrap_or(AuthMode::Enforce);
+        let (key_id, service) = match key_id {
+            Some(key_id) if mode != AuthMode::Disabled => (key_id, self.service.clone()),
+            _ => return Box::pin(self.service.call(req))
+        };
+        let key_store = self.key_store.clone();
+        let nonces = self.nonces.clone();
+
+        Box::pin(async move {
+            let (req, body) = read_body(req).await?;
+            if let Err(msg) = check_signature(&req, &body, key_id, &key_store, &nonces) {
+                if mode == AuthMode::Enforce {
+                    return Err(BrokerError::Unauthorized(msg).into());
+                }
+                warn!("{} {} would have been refused: {}", req.method(), req.path(), msg);
+            }
+            service.call(req).await
+        })
+    }
+}
+
+fn check_signature(req : &ServiceRequest, body : &[u8], key_id : i32, key_store : &KeyStore, nonces : &NonceCache) -> Result<(), String> {
+    let header = |name : &str| req.headers().get(name).and_then(|value| value.to_str().ok())
+        .ok_or_else(|| format!("API key {} only accepts signed requests, `{}` is missing", key_id, name));
+    let timestamp = header(signing::TIMESTAMP_HEADER)?;
+    let nonce = header(signing::NONCE_HEADER)?;
+    let signature = header(signing::SIGNATURE_HEADER)?;
+    let message = signing::string_to_sign(timestamp, nonce, req.method().as_str(), req.path(), req.query_string(), body);
+    if !key_store.verify_signature(key_id, message.as_bytes(), signature) {
+        return Err(String::from("Request signature is not valid"));
+    }
+    let timestamp = timestamp.parse::<i64>().map_err(|_| String::from("Request timestamp must be in unix seconds"))?;
+    nonces.check(key_id, timestamp, nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, http::Method, http::StatusCode};
+    use chrono::Utc;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use crate::types::{ApiKeyRecord, Scope};
+
+    const SECRET : &str = "0123456789abcdef";
+
+    fn key_store() -> KeyStore {
+        let key_store = KeyStore::default();
+        key_store.replace(vec![ApiKeyRecord {
+            id : 1,
+            salt : Vec::new(),
+            key_hash : Vec::new(),
+            scopes : vec![Scope::Trade],
+            expires_at : None,
+            read_limit : None,
+            trade_limit : None,
+            signing_secret : Some(String::from(SECRET)),
+            legacy : false
+        }]);
+        key_store
+    }
+
+    /// A request with its signature headers. `signed` is what was signed: method, path and query, and body
+    fn request(method : Method, uri : &str, body : &'static str, timestamp : i64, nonce : &str, signed : (&str, &str, &str, &str)) -> test::TestRequest {
+        let (signed_method, signed_path, signed_query, signed_body) = signed;
+        let message = signing::string_to_sign(&timestamp.to_string(), nonce, signed_method, signed_path, signed_query, signed_body.as_bytes());
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        test::TestRequest::default()
+            .method(method)
+            .uri(uri)
+            .insert_header((signing::TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((signing::NONCE_HEADER, nonce))
+            .insert_header((signing::SIGNATURE_HEADER, hex::encode(mac.finalize().into_bytes())))
+            .set_payload(body)
+    }
+
+    /// Sends `req` as signed key 1 through `SignatureService`, to a handler that echoes the body
+    async fn send(mode : AuthMode, nonces : &NonceCache, req : test::TestRequest) -> (StatusCode, web::Bytes) {
+        let app = test::init_service(
+            App::new()
+                .default_service(web::route().to(|body : web::Bytes| async move { body }))
+                .wrap(SignatureService::new(key_store(), nonces.clone()))
+                .wrap_fn(move |req, srv| {
+                    let key = AuthenticatedKey { id : Some(1), scopes : vec![Scope::Trade], read_limit : None, trade_limit : None, signed : true };
+                    req.extensions_mut().insert(key);
+                    req.extensions_mut().insert(mode);
+                    srv.call(req)
+                })
+        ).await;
+        match app.call(req.to_request()).await {
+            Ok(res) => (res.status(), test::read_body(res).await),
+            Err(e) => (e.as_response_error().status_code(), web::Bytes::new())
+        }
+    }
+
+    fn nonces() -> NonceCache {
+        NonceCache::new(std::time::Duration::from_secs(300))
+    }
+
+    #[tokio::test]
+    async fn lets_through_a_valid_signature() {
+        let now = Utc::now().timestamp();
+        let req = request(Method::POST, "/buy?a=1", r#"{"userId":"u1"}"#, now, "n1", ("POST", "/buy", "a=1", r#"{"userId":"u1"}"#));
+        let (status, body) = send(AuthMode::Enforce, &nonces(), req).await;
+        assert_eq!(status, StatusCode::OK);
+        // the handler still gets the body the middleware read
+        assert_eq!(body, web::Bytes::from_static(br#"{"userId":"u1"}"#));
+    }
+
+    #[tokio::test]
+    async fn refuses_tampered_requests() {
+        let now = Utc::now().timestamp();
+        let nonces = nonces();
+        for req in [
+            request(Method::POST, "/buy", r#"{"qty":9}"#, now, "body", ("POST", "/buy", "", r#"{"qty":1}"#)),
+            request(Method::GET, "/balance?userId=u2", "", now, "query", ("GET", "/balance", "userId=u1", "")),
+            request(Method::DELETE, "/orders/1", "", now, "method", ("GET", "/orders/1", "", "")),
+            request(Method::GET, "/portfolio", "", now, "path", ("GET", "/balance", "", "")),
+            test::TestRequest::get().uri("/balance")
+        ] {
+            assert_eq!(send(AuthMode::Enforce, &nonces, req).await.0, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_timestamps_outside_the_skew_window() {
+        let now = Utc::now().timestamp();
+        let nonces = nonces();
+        for timestamp in [now - 301, now + 301] {
+            let req = request(Method::GET, "/balance", "", timestamp, "n1", ("GET", "/balance", "", ""));
+            assert_eq!(send(AuthMode::Enforce, &nonces, req).await.0, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_replays() {
+        let now = Utc::now().timestamp();
+        let nonces = nonces();
+        let req = || request(Method::GET, "/balance", "", now, "n1", ("GET", "/balance", "", ""));
+        assert_eq!(send(AuthMode::Enforce, &nonces, req()).await.0, StatusCode::OK);
+        assert_eq!(send(AuthMode::Enforce, &nonces, req()).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn bad_signatures_dont_use_up_the_nonce() {
+        let now = Utc::now().timestamp();
+        let nonces = nonces();
+        let forged = request(Method::GET, "/balance", "", now, "n1", ("GET", "/portfolio", "", ""));
+        assert_eq!(send(AuthMode::Enforce, &nonces, forged).await.0, StatusCode::UNAUTHORIZED);
+        let genuine = request(Method::GET, "/balance", "", now, "n1", ("GET", "/balance", "", ""));
+        assert_eq!(send(AuthMode::Enforce, &nonces, genuine).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_logs_in_log_only_mode() {
+        let req = test::TestRequest::get().uri("/balance");
+        assert_eq!(send(AuthMode::LogOnly, &nonces(), req).await.0, StatusCode::OK);
+    }
+}
@@ -1,33 +1,69 @@
-use tokio_postgres::{Config as PgConfig,Row,NoTls,Client};
-use crate::config::DataSource;
+use tokio_postgres::{Config as PgConfig,Row,NoTls,Client,AsyncMessage};
+use bb8::{Pool,ManageConnection};
+use async_trait::async_trait;
+use crate::config::{DataSource,PoolConfig,DailyRewardConfig,QuoteConfig,FeeConfig};
 use crate::types::*;
+use crate::errors::{BrokerError,BrokerResult};
 use std::convert::TryFrom;
 use crate::api::types::*;
+use crate::accounting::{cost_basis,CostBasisMethod};
+use crate::ingest::MarketQuote;
 
+/// Opens plain (non TLS) connections for the pool, spinning each connection's driver off onto its own task.
 #[derive(Debug)]
-pub struct BrokerMapper {
+pub struct PgConnectionManager {
   config : PgConfig,
 }
 
+#[async_trait]
+impl ManageConnection for PgConnectionManager {
+  type Connection = Client;
+  type Error = tokio_postgres::Error;
+
+  async fn connect(&self) -> Result<Client, Self::Error> {
+    let (client,conn) = self.config.connect(NoTls).await?;
+    tokio::spawn(async move{
+      if let Err(e) = conn.await {
+        eprintln!("connection error: {}",e);
+      }
+    });
+    Ok(client)
+  }
+
+  async fn is_valid(&self, client : &mut Client) -> Result<(), Self::Error> {
+    client.simple_query("").await.map(|_| ())
+  }
+
+  fn has_broken(&self, client : &mut Client) -> bool {
+    client.is_closed()
+  }
+}
+
+#[derive(Debug,Clone)]
+pub struct BrokerMapper {
+  pool : Pool<PgConnectionManager>,
+  /// Trades are refused, and coins flagged `stale`, when their newest price is older than this
+  max_quote_age : Option<std::time::Duration>,
+  /// Fees for servers without their own in `serverfees`
+  fees : FeeConfig,
+  /// Where `listen` connects, since its connection can't come from the pool
+  listen_config : PgConfig
+}
+
 macro_rules! get_client {
   ($a : ident) => {
-    {
-      let (client,conn) = $a.connect(NoTls).await?;
-      tokio::spawn(async move{
-        if let Err(e) = conn.await {
-          eprintln!("connection error: {}",e);
-        }
-      });
-      client
-    }
+    $a.pool.get().await?
   };
 }
 
+/// `serverId` of the wallet used when a request doesn't name a server
+pub const GLOBAL_WALLET : &str = "";
+
 #[inline(always)]
-pub fn push<'a>(base : &str, suffix : &str) -> String {
+pub fn push(base : &str, suffix : &str) -> String {
     let mut heap_base = String::from(base);
     heap_base.push_str(suffix);
-    return heap_base;
+    heap_base
 }
 
 impl BrokerMapper {
@@ -59,80 +95,320 @@ impl BrokerMapper {
   )
   "#;
   
-  pub fn new(ds : &DataSource) -> BrokerMapper {
-    return BrokerMapper{config : ds.into()};
+  /// Builds the shared connection pool. Connections are opened lazily on first checkout.
+  pub fn new(ds : &DataSource, pool_config : &PoolConfig, quote_config : &QuoteConfig, fee_config : &FeeConfig) -> BrokerMapper {
+    let manager = PgConnectionManager { config : ds.into() };
+    let pool = Pool::builder()
+      .max_size(pool_config.max_size)
+      .min_idle(Some(0))
+      .idle_timeout(Some(pool_config.idle_timeout()))
+      .connection_timeout(pool_config.checkout_timeout())
+      .build_unchecked(manager);
+    BrokerMapper{pool, max_quote_age : quote_config.max_age(), fees : fee_config.clone(), listen_config : ds.into()}
+  }
+
+  /// Whether a price taken at `as_of` is too old to trade at
+  fn is_stale(&self, as_of : &chrono::DateTime<chrono::Utc>) -> bool {
+    self.max_quote_age.is_some_and(|max_age| (chrono::Utc::now() - *as_of).num_seconds() > max_age.as_secs() as i64)
+  }
+
+  fn max_quote_age_secs(&self) -> Option<f64> {
+    self.max_quote_age.map(|age| age.as_secs_f64())
+  }
+
+  /// `asOf` of the newest price of any coin
+  pub async fn newest_quote_at(&self) -> BrokerResult<Option<chrono::DateTime<chrono::Utc>>> {
+    let client = get_client!(self);
+    let newest : Option<chrono::NaiveDateTime> = client.query_one("SELECT MAX(asOf) FROM cryptodata;", &[]).await?.try_get(0)?;
+    Ok(newest.map(|ts| chrono::DateTime::from_utc(ts, chrono::Utc)))
+  }
+
+  pub fn max_quote_age(&self) -> Option<std::time::Duration> {
+    self.max_quote_age
   }
   
-  pub async fn list_currencies(&self) -> StdResult<Vec<CurrencyData>> {
+  pub async fn list_currencies(&self) -> BrokerResult<Vec<CurrencyData>> {
     let currency_list_query = format!("{} {}",BrokerMapper::CTE_LATEST_LIST,r#"
     SELECT * FROM cteLatestPrices 
     ORDER BY market_cap DESC LIMIT 200;
     "#);
     let query = currency_list_query.as_str();
-    let conf = self.config.clone();
-    let client = get_client!(conf);
-    // prob have to spin the connection off
+    let client = get_client!(self);
     let mut currency_list = Vec::<CurrencyData>::new();
     for row in client.query(query,&[]).await? {
-      currency_list.push(CurrencyData::try_from(&row)?);
+      let mut currency = CurrencyData::try_from(&row)?;
+      currency.stale = self.is_stale(&currency.as_of);
+      currency_list.push(currency);
     }
     Ok(currency_list)
   }
 
-  pub async fn api_keys(&self) -> StdResult<Vec<String>> {
-    let config = self.config.clone();
-    let client = get_client!(config);
+  pub async fn api_keys(&self) -> BrokerResult<Vec<ApiKeyRecord>> {
+    let client = get_client!(self);
     let query = r#"
-    SELECT key_str FROM apikeys
+    SELECT id, salt, keyHash, scopes, expiresAt, readLimit, tradeLimit, signingSecret, legacy FROM apikeys
+    WHERE revokedAt IS NULL AND (expiresAt IS NULL OR expiresAt > NOW())
     "#;
     let key_rows = client.query(query, &[]).await?;
-    Ok(key_rows.into_iter().map(|row| row.get::<usize, &str>(0).to_string()).collect())
+    Ok(key_rows.iter().map(ApiKeyRecord::try_from).collect::<Result<Vec<ApiKeyRecord>,_>>()?)
+  }
+
+  pub async fn record_api_key_use(&self, last_used : &std::collections::HashMap<i32, chrono::DateTime<chrono::Utc>>) -> BrokerResult<()> {
+    if last_used.is_empty() {
+      return Ok(());
+    }
+    let (ids, used_at) : (Vec<i32>, Vec<chrono::NaiveDateTime>) = last_used.iter().map(|(id, ts)| (*id, ts.naive_utc())).unzip();
+    let client = get_client!(self);
+    let query = r#"
+    UPDATE apikeys k SET lastUsedAt = u.usedAt
+    FROM unnest($1::INT[], $2::TIMESTAMP[]) AS u(id, usedAt)
+    WHERE k.id = u.id AND (k.lastUsedAt IS NULL OR k.lastUsedAt < u.usedAt)
+    "#;
+    client.execute(query, &[&ids, &used_at]).await?;
+    Ok(())
+  }
+
+  pub async fn list_api_keys(&self) -> BrokerResult<Vec<ApiKeyInfo>> {
+    let client = get_client!(self);
+    Ok(
+      client.query("SELECT * FROM vApiKeys ORDER BY id", &[]).await?
+      .iter()
+      .map(ApiKeyInfo::try_from)
+      .collect::<Result<Vec<ApiKeyInfo>,_>>()?
+    )
+  }
+
+  /// Makes a key through `create_api_key`, recording `actor_key_id` as the admin that made it
+  pub async fn create_api_key(&self, request : &CreateApiKeyRequest, actor_key_id : Option<i32>) -> BrokerResult<CreatedApiKey> {
+    let client = get_client!(self);
+    let scopes : Vec<&str> = request.scopes.iter().map(Scope::as_str).collect();
+    let key : String = client.query_one(
+      "SELECT create_api_key($1,$2,$3,$4,$5,$6) AS key",
+      &[&scopes, &request.description, &actor_key_id, &request.read_limit.map(|l| l as i32), &request.trade_limit.map(|l| l as i32), &request.signed]
+    ).await?.try_get("key")?;
+    BrokerMapper::created_api_key(&client, key).await
+  }
+
+  /// Replaces a working key with a new one through `rotate_api_key`. The old key keeps working for `grace`
+  pub async fn rotate_api_key(&self, key_id : i32, grace : std::time::Duration, actor_key_id : Option<i32>) -> BrokerResult<RotatedApiKey> {
+    let client = get_client!(self);
+    let rotated = client.query_opt(
+      "SELECT rotate_api_key(id, make_interval(secs => $2), $3) AS key FROM apikeys WHERE id = $1",
+      &[&key_id, &grace.as_secs_f64(), &actor_key_id]
+    ).await?;
+    match rotated {
+      None => Err(BrokerError::NotFound(format!("No API key {} found", key_id))),
+      Some(row) => match row.try_get::<&str,Option<String>>("key")? {
+        None => Err(BrokerError::Conflict(format!("API key {} is revoked or expired", key_id))),
+        Some(key) => Ok(RotatedApiKey {
+          key : BrokerMapper::created_api_key(&client, key).await?,
+          replaces : BrokerMapper::api_key_by_id(&client, key_id).await?
+        })
+      }
+    }
+  }
+
+  /// Sets a key's own rate limits through `set_api_key_limits`
+  pub async fn set_api_key_limits(&self, key_id : i32, limits : &ApiKeyLimits, actor_key_id : Option<i32>) -> BrokerResult<ApiKeyInfo> {
+    let client = get_client!(self);
+    let updated = client.query_opt(
+      "SELECT set_api_key_limits(id, $2, $3, $4) FROM apikeys WHERE id = $1",
+      &[&key_id, &limits.read_limit.map(|l| l as i32), &limits.trade_limit.map(|l| l as i32), &actor_key_id]
+    ).await?;
+    match updated {
+      None => Err(BrokerError::NotFound(format!("No API key {} found", key_id))),
+      Some(_) => BrokerMapper::api_key_by_id(&client, key_id).await
+    }
+  }
+
+  /// Makes a key need signed requests, or stop needing them, through `set_api_key_signing`
+  pub async fn set_api_key_signing(&self, key_id : i32, signed : bool, actor_key_id : Option<i32>) -> BrokerResult<ApiKeySigning> {
+    let client = get_client!(self);
+    let updated = client.query_opt(
+      "SELECT set_api_key_signing(id, $2, $3) AS signingSecret FROM apikeys WHERE id = $1", &[&key_id, &signed, &actor_key_id]
+    ).await?;
+    match updated {
+      None => Err(BrokerError::NotFound(format!("No API key {} found", key_id))),
+      Some(row) => Ok(ApiKeySigning {
+        signing_secret : row.try_get("signingSecret")?,
+        info : BrokerMapper::api_key_by_id(&client, key_id).await?
+      })
+    }
+  }
+
+  /// Stops a key from working through `revoke_api_key`
+  pub async fn revoke_api_key(&self, key_id : i32, actor_key_id : Option<i32>) -> BrokerResult<ApiKeyInfo> {
+    let client = get_client!(self);
+    let revoked = client.query_opt(
+      "SELECT revoke_api_key(id, $2) AS revoked FROM apikeys WHERE id = $1", &[&key_id, &actor_key_id]
+    ).await?;
+    match revoked {
+      None => Err(BrokerError::NotFound(format!("No API key {} found", key_id))),
+      Some(row) if !row.try_get::<&str,bool>("revoked")? => Err(BrokerError::Conflict(format!("API key {} is already revoked", key_id))),
+      Some(_) => BrokerMapper::api_key_by_id(&client, key_id).await
+    }
+  }
+
+  async fn api_key_by_id(client : &Client, key_id : i32) -> BrokerResult<ApiKeyInfo> {
+    Ok(ApiKeyInfo::try_from(&client.query_one("SELECT * FROM vApiKeys WHERE id = $1", &[&key_id]).await?)?)
+  }
+
+  /// Pairs a `<id>.<secret>` key fresh from the database with its row and signing secret
+  async fn created_api_key(client : &Client, key : String) -> BrokerResult<CreatedApiKey> {
+    let key_id = key.split_once('.').and_then(|(id, _)| id.parse().ok())
+      .ok_or_else(|| BrokerError::Internal(String::from("create_api_key returned a malformed key")))?;
+    let signing_secret = client.query_one("SELECT signingSecret FROM apikeys WHERE id = $1", &[&key_id]).await?.try_get("signingSecret")?;
+    Ok(CreatedApiKey { info : BrokerMapper::api_key_by_id(client, key_id).await?, signing_secret, key })
+  }
+
+  /// Opens a connection outside the pool that `LISTEN`s on `channel`, waking `notify` for every notification. Returns
+  /// once the connection is lost
+  pub async fn listen(&self, channel : &str, notify : std::sync::Arc<tokio::sync::Notify>) -> BrokerResult<()> {
+    let (client, mut conn) = self.listen_config.connect(NoTls).await?;
+    let driver = tokio::spawn(async move {
+      while let Some(message) = std::future::poll_fn(|cx| conn.poll_message(cx)).await {
+        if let AsyncMessage::Notification(_) = message? {
+          notify.notify_one();
+        }
+      }
+      Ok::<(), tokio_postgres::Error>(())
+    });
+    client.batch_execute(&format!("LISTEN {}", channel)).await?;
+    match driver.await {
+      Ok(result) => Ok(result?),
+      Err(e) => Err(BrokerError::Internal(e.to_string()))
+    }
   }
   
-  pub async fn get_latest_price<S : AsRef<str>>(&self, symbol : S) -> StdResult<Numeric> {
+  pub async fn get_latest_price<S : AsRef<str>>(&self, symbol : S) -> BrokerResult<Numeric> {
     let query = r#"
     SELECT price FROM cryptodata where LOWER(symbol) = LOWER($1) ORDER BY asOf DESC LIMIT 1;
     "#;
-    let config = self.config.clone();
-    let client = get_client!(config);
-    let price : Numeric = client.query_one(query,&[&symbol.as_ref()]).await?.try_get("price")?;
-    Ok(price)
+    let client = get_client!(self);
+    match client.query_opt(query,&[&symbol.as_ref()]).await? {
+      Some(row) => Ok(row.try_get("price")?),
+      None => Err(BrokerError::NotFound(format!("No price found for {}", symbol.as_ref())))
+    }
   }
   
-  pub async fn get_wallet_balance_by_userid<S : AsRef<str>>(&self, user_id : &S) -> StdResult<Numeric> {
-    let conf = self.config.clone();
-    let client = get_client!(conf);
+  pub async fn get_wallet_balance_by_userid<S : AsRef<str>>(&self, user_id : &S, server_id : Option<&str>) -> BrokerResult<Numeric> {
+    let client = get_client!(self);
+    BrokerMapper::wallet_balance(&client, user_id.as_ref(), server_id.unwrap_or(GLOBAL_WALLET)).await
+  }
+
+  /// Reads a wallet balance on an already borrowed connection, so callers that need it mid-request don't check out a second one.
+  async fn wallet_balance(client : &Client, user_id : &str, server_id : &str) -> BrokerResult<Numeric> {
     let query = r#"
-    SELECT walletBalance FROM wallet WHERE userId = $1 LIMIT 1;
+    SELECT walletBalance FROM wallet WHERE userId = $1 AND serverId = $2 LIMIT 1;
     "#;
-    let amount : Numeric = client.query_one(query, &[&user_id.as_ref()]).await?.try_get("walletBalance")?;
-    return Ok(amount);
+    match client.query_opt(query, &[&user_id, &server_id]).await? {
+      Some(row) => Ok(row.try_get("walletBalance")?),
+      None if server_id == GLOBAL_WALLET => Err(BrokerError::NotFound(format!("No wallet found for user {}", user_id))),
+      None => Err(BrokerError::NotFound(format!("No wallet found for user {} on server {}", user_id, server_id)))
+    }
   }
 
-  pub async fn set_wallet_balance_by_userid(&self, user_id : &str, bal : Numeric) -> StdResult<()> {
-    let conf = self.config.clone();
-    let client = get_client!(conf);
-    let query = r#"
+  /// Pays out today's reward, creating the wallet if needed. The claim row and the wallet credit are written in one
+  /// transaction, and the primary key on `(userId, serverId, claimDate)` makes a second claim on the same UTC day a no-op.
+  pub async fn claim_daily_reward(&self, user_id : &str, server_id : Option<&str>, reward : &DailyRewardConfig) -> BrokerResult<DailyRewardClaim> {
+    let server_id = server_id.unwrap_or(GLOBAL_WALLET);
+    let mut client = get_client!(self);
+    let tx = client.transaction().await?;
+    let claim = tx.query_opt(r#"
+    INSERT INTO dailyrewards (userId, serverId, claimDate, streak, amount)
+    SELECT $1, $5, cd.today, cs.streak, $2::NUMERIC + $3::NUMERIC * LEAST(cs.streak - 1, $4)
+    FROM
+      (SELECT (NOW() AT TIME ZONE 'UTC')::date AS today) cd,
+      LATERAL (SELECT COALESCE(
+        (SELECT d.streak FROM dailyrewards d WHERE d.userId = $1 AND d.serverId = $5 AND d.claimDate = cd.today - 1), 0
+      ) + 1 AS streak) cs
+    ON CONFLICT (userId, serverId, claimDate) DO NOTHING
+    RETURNING streak, amount;
+    "#, &[&user_id, &reward.base_amount, &reward.streak_bonus, &reward.max_streak_bonus_days, &server_id]).await?;
+    let next_claim = tx.query_one(r#"
+    SELECT
+      date_trunc('day', NOW() AT TIME ZONE 'UTC') + interval '1 day' AS nextClaimAt,
+      EXTRACT(EPOCH FROM date_trunc('day', NOW() AT TIME ZONE 'UTC') + interval '1 day' - (NOW() AT TIME ZONE 'UTC'))::bigint AS secsUntil;
+    "#, &[]).await?;
+    let claim = match claim {
+      Some(row) => row,
+      None => {
+        let retry_after_secs : i64 = next_claim.try_get("secsUntil")?;
+        return Err(BrokerError::Cooldown {
+          msg : format!("Daily reward already claimed, next claim in {}h {}m", retry_after_secs / 3600, retry_after_secs % 3600 / 60),
+          retry_after_secs
+        });
+      }
+    };
+    let amount : Numeric = claim.try_get("amount")?;
+    let balance : Numeric = tx.query_one(r#"
     INSERT INTO
-      wallet (userId, walletBalance)
+      wallet (userId, serverId, walletBalance)
     VALUES
-      ($1, $2)
-    ON CONFLICT (userId)
+      ($1, $2, $3)
+    ON CONFLICT (userId, serverId)
     DO
-      UPDATE SET walletBalance = $2;
-    "#;
-    client.execute(query, &[&user_id, &bal]).await?;
-    Ok(())
+      UPDATE SET walletBalance = wallet.walletBalance + EXCLUDED.walletBalance
+    RETURNING walletBalance;
+    "#, &[&user_id, &server_id, &amount]).await?.try_get("walletBalance")?;
+    tx.commit().await?;
+    Ok(DailyRewardClaim {
+      user_id : user_id.to_string(),
+      server_id : Some(server_id.to_string()).filter(|s| s != GLOBAL_WALLET),
+      amount,
+      streak : claim.try_get("streak")?,
+      balance,
+      next_claim_at : chrono::DateTime::from_utc(next_claim.try_get::<&str,chrono::NaiveDateTime>("nextClaimAt")?, chrono::Utc)
+    })
   }
   
-  pub async fn buy_currency<S : AsRef<str>>(&self, crypto_id : &S, qty : &Numeric, user_id : &S) -> StdResult<()> {
-    let conf = self.config.clone();
-    let client : Client = get_client!(conf);
-    client.execute("SELECT * FROM buy_currency($2,$1,$3)", &[&crypto_id.as_ref(),qty,&user_id.as_ref()]).await?;
-    Ok(())
+  /// Buys `size` of a coin at the latest price
+  pub async fn buy_currency<S : AsRef<str>>(&self, crypto_id : &S, size : TradeSize, user_id : &S, server_id : Option<&str>) -> BrokerResult<TradeReceipt> {
+    let (qty, amount) = BrokerMapper::trade_size_params(size);
+    let mut client = get_client!(self);
+    let tx = client.transaction().await?;
+    let transaction_id : i32 = tx.query_one(
+      "SELECT buy_currency($2,$1,$3,$4,make_interval(secs => $5),NULL,$6,trade_fees($4,$7,$8,$9))",
+      &[&crypto_id.as_ref(),&qty,&user_id.as_ref(),&server_id.unwrap_or(GLOBAL_WALLET),&self.max_quote_age_secs(),&amount,
+        &self.fees.commission_pct,&self.fees.min_fee,&self.fees.spread_pct]
+    ).await?.try_get(0)?;
+    let receipt = BrokerMapper::trade_receipt(&tx, transaction_id).await?;
+    tx.commit().await?;
+    Ok(receipt)
+  }
+
+  /// The `qty` and `l_amount` arguments of `buy_currency` / `sell_currency` for `size`. Selling with neither sells everything
+  fn trade_size_params(size : TradeSize) -> (Option<Numeric>, Option<Numeric>) {
+    match size {
+      TradeSize::Qty(qty) => (Some(qty), None),
+      TradeSize::Amount(amount) => (None, Some(amount)),
+      TradeSize::All => (None, None)
+    }
+  }
+
+  /// Reads back the fill of `transaction_id`, and what is left of the position, inside the transaction that made it
+  async fn trade_receipt(tx : &tokio_postgres::Transaction<'_>, transaction_id : i32) -> BrokerResult<TradeReceipt> {
+    let fill = tx.query_one(r#"
+    SELECT
+      t.cryptoId,
+      ABS(t.qty) AS qty,
+      ABS(t.cost + t.fee) AS total,
+      COALESCE(t.price, t.cost / t.qty) AS price,
+      t.fee,
+      (SELECT COALESCE(SUM(h.qty), 0) FROM transactions h WHERE h.userId = t.userId AND h.serverId = t.serverId AND h.cryptoId = t.cryptoId) AS remaining
+    FROM transactions t WHERE t.transactionId = $1;
+    "#, &[&transaction_id]).await?;
+    Ok(TradeReceipt {
+      crypto_id : fill.try_get("cryptoId")?,
+      qty : fill.try_get("qty")?,
+      price : fill.try_get("price")?,
+      total : fill.try_get("total")?,
+      fee : fill.try_get("fee")?,
+      remaining_qty : fill.try_get("remaining")?
+    })
   }
 
-  pub async fn get_coins_matching_key(&self, coin_key : &CoinIdentifierKey) -> StdResult<Vec<CurrencyData>> {
+  pub async fn get_coins_matching_key(&self, coin_key : &CoinIdentifierKey) -> BrokerResult<Vec<CurrencyData>> {
     let where_conds;
     let param : &String;
     if let Some(crypto_id) = &coin_key.crypto_id {
@@ -147,42 +423,490 @@ impl BrokerMapper {
       where_conds = "lower(symbol) = lower($1)";
       param = symbol;
     } else {
-      return Err(new_std_err("Please spcify an id, name, or symbol"));
+      return Err(BrokerError::Validation(String::from("Please specify an id, name, or symbol")));
     }
     let query_tail = format!("{} {} {}",BrokerMapper::CTE_LATEST_LIST,r#"
     SELECT * FROM cteLatestPrices 
     WHERE "#,where_conds);
     let query = query_tail.as_str();
-    let config = self.config.clone();
-    let client : Client = get_client!(config);
+    let client = get_client!(self);
     Ok(
       client.query(query, &[param]).await?
       .iter()
-      .map(|r| CurrencyData::try_from(r).expect("Could not map currency data from row"))
-      .collect()
+      .map(|row| CurrencyData::try_from(row).map(|mut currency| { currency.stale = self.is_stale(&currency.as_of); currency }))
+      .collect::<Result<Vec<CurrencyData>,_>>()?
     )
   }
   
-  #[allow(unused_variables)]
-  pub async fn sell_currency<S : AsRef<str>>(&self, crypto_id : &S, qty : &Numeric, user_id : &S) -> StdResult<()> {
-    // check they have enough 
-    let conf = self.config.clone();
-    let client : Client = get_client!(conf);
-    client.execute("SELECT * FROM buy_currency($1,$2,$3)", &[qty,&crypto_id.as_ref(),&user_id.as_ref()]).await?;
-    Ok(())
+  /// Downsamples a coin's snapshots between `from` (inclusive) and `to` (exclusive) into buckets of `resolution`,
+  /// keeping the last snapshot taken in each bucket.
+  pub async fn price_history(&self, crypto_id : &str, from : &chrono::DateTime<chrono::Utc>, to : &chrono::DateTime<chrono::Utc>, resolution : &Interval) -> BrokerResult<Vec<PricePoint>> {
+    let client = get_client!(self);
+    let query = r#"
+    SELECT asOf, price, market_cap, volume FROM (
+      SELECT DISTINCT ON (bucket) asOf, price, market_cap, volume
+      FROM (
+        SELECT FLOOR(EXTRACT(EPOCH FROM asOf)::FLOAT8 / $4::FLOAT8) AS bucket, asOf, price, market_cap, volume
+        FROM cryptodata
+        WHERE id = $1 AND asOf >= $2 AND asOf < $3
+      ) AS snapshots
+      ORDER BY bucket, asOf DESC
+    ) AS buckets
+    ORDER BY asOf;
+    "#;
+    let resolution_secs = resolution.seconds() as f64;
+    Ok(
+      client.query(query, &[&crypto_id, &from.naive_utc(), &to.naive_utc(), &resolution_secs]).await?
+      .iter()
+      .map(PricePoint::try_from)
+      .collect::<Result<Vec<PricePoint>,_>>()?
+    )
+  }
+
+  /// Aggregates a coin's snapshots between `from` (inclusive) and `to` (exclusive) into candles of `interval`.
+  /// Buckets are aligned to the unix epoch and ones without any snapshot are skipped.
+  pub async fn candles(&self, crypto_id : &str, from : &chrono::DateTime<chrono::Utc>, to : &chrono::DateTime<chrono::Utc>, interval : &Interval) -> BrokerResult<Vec<Candle>> {
+    let client = get_client!(self);
+    let query = r#"
+    SELECT
+      TO_TIMESTAMP(bucket * $4::FLOAT8) AT TIME ZONE 'UTC' AS openTime,
+      (ARRAY_AGG(price ORDER BY asOf))[1] AS open,
+      MAX(price) AS high,
+      MIN(price) AS low,
+      (ARRAY_AGG(price ORDER BY asOf DESC))[1] AS close,
+      (ARRAY_AGG(volume ORDER BY asOf DESC))[1] AS volume,
+      COUNT(*) AS samples
+    FROM (
+      SELECT FLOOR(EXTRACT(EPOCH FROM asOf)::FLOAT8 / $4::FLOAT8) AS bucket, asOf, price, volume
+      FROM cryptodata
+      WHERE id = $1 AND asOf >= $2 AND asOf < $3
+    ) AS snapshots
+    GROUP BY bucket
+    ORDER BY bucket;
+    "#;
+    let interval_secs = interval.seconds() as f64;
+    Ok(
+      client.query(query, &[&crypto_id, &from.naive_utc(), &to.naive_utc(), &interval_secs]).await?
+      .iter()
+      .map(Candle::try_from)
+      .collect::<Result<Vec<Candle>,_>>()?
+    )
+  }
+
+  /// A wallet's net worth from `from` to `to`, about one point per `step`. Snapshots are used where they exist, and the
+  /// part of the window before the first snapshot (e.g. from before snapshots were turned on) is replayed from the ledger.
+  pub async fn networth_series(&self, user_id : &str, server_id : Option<&str>, from : &chrono::DateTime<chrono::Utc>, to : &chrono::DateTime<chrono::Utc>, step : &Interval) -> BrokerResult<Vec<NetWorthPoint>> {
+    let snapshots = self.networth_snapshots(user_id, server_id, from, to, step).await?;
+    let replay_to = snapshots.first().map_or(*to, |s| s.as_of);
+    let mut points = if replay_to > *from {
+      self.replay_networth(user_id, server_id, from, &replay_to, step).await?
+    } else {
+      Vec::new()
+    };
+    if !snapshots.is_empty() {
+      // the replay includes its end bound, which is the first snapshot's timestamp
+      points.retain(|p| p.as_of < replay_to);
+    }
+    points.extend(snapshots.into_iter().map(|s| NetWorthPoint { as_of : s.as_of, net_worth : s.net_worth }));
+    Ok(points)
+  }
+
+  /// A wallet's net worth every `step` from `from` to `to`, worked back from the ledger by `networth_history` in schema.sql.
+  /// Empty when the wallet doesn't exist.
+  async fn replay_networth(&self, user_id : &str, server_id : Option<&str>, from : &chrono::DateTime<chrono::Utc>, to : &chrono::DateTime<chrono::Utc>, step : &Interval) -> BrokerResult<Vec<NetWorthPoint>> {
+    let client = get_client!(self);
+    let query = "SELECT asOf, netWorth FROM networth_history($1, $2, $3, $4, make_interval(secs => $5));";
+    let step_secs = step.seconds() as f64;
+    Ok(
+      client.query(query, &[&user_id, &server_id.unwrap_or(GLOBAL_WALLET), &from.naive_utc(), &to.naive_utc(), &step_secs]).await?
+      .iter()
+      .map(NetWorthPoint::try_from)
+      .collect::<Result<Vec<NetWorthPoint>,_>>()?
+    )
+  }
+
+  /// A wallet's snapshots between `from` (inclusive) and `to` (exclusive), keeping the last one in each `resolution` bucket.
+  pub async fn networth_snapshots(&self, user_id : &str, server_id : Option<&str>, from : &chrono::DateTime<chrono::Utc>, to : &chrono::DateTime<chrono::Utc>, resolution : &Interval) -> BrokerResult<Vec<NetWorthSnapshot>> {
+    let client = get_client!(self);
+    let query = r#"
+    SELECT snapshotAt, cash, portfolioValue, netWorth FROM (
+      SELECT DISTINCT ON (bucket) snapshotAt, cash, portfolioValue, netWorth
+      FROM (
+        SELECT FLOOR(EXTRACT(EPOCH FROM snapshotAt)::FLOAT8 / $5::FLOAT8) AS bucket, snapshotAt, cash, portfolioValue, netWorth
+        FROM networthsnapshots
+        WHERE userId = $1 AND serverId = $2 AND snapshotAt >= $3 AND snapshotAt < $4
+      ) AS snapshots
+      ORDER BY bucket, snapshotAt DESC
+    ) AS buckets
+    ORDER BY snapshotAt;
+    "#;
+    let resolution_secs = resolution.seconds() as f64;
+    Ok(
+      client.query(query, &[&user_id, &server_id.unwrap_or(GLOBAL_WALLET), &from.naive_utc(), &to.naive_utc(), &resolution_secs]).await?
+      .iter()
+      .map(NetWorthSnapshot::try_from)
+      .collect::<Result<Vec<NetWorthSnapshot>,_>>()?
+    )
+  }
+
+  /// Snapshots every wallet for the current `every` sized interval, returning how many were written
+  pub async fn snapshot_networth(&self, every : std::time::Duration) -> BrokerResult<i32> {
+    let client = get_client!(self);
+    let every_secs = every.as_secs_f64();
+    Ok(client.query_one("SELECT snapshot_networth(make_interval(secs => $1));", &[&every_secs]).await?.try_get(0)?)
+  }
+
+  /// Writes one `cryptodata` row per quote in a single statement, all with the same `asOf`. Quotes without a price are
+  /// skipped, as are repeats of a coin already in the batch. Returns the number of rows written.
+  pub async fn insert_market_quotes(&self, quotes : &[MarketQuote]) -> BrokerResult<u64> {
+    let quotes : Vec<&MarketQuote> = quotes.iter().filter(|q| q.current_price.is_some_and(|p| p > Numeric::ZERO)).collect();
+    if quotes.is_empty() {
+      return Ok(0);
+    }
+    let client = get_client!(self);
+    let query = r#"
+    INSERT INTO cryptodata (id, symbol, name, price, image_url, market_cap, volume, coingecko_timestamp)
+    SELECT * FROM UNNEST($1::VARCHAR[], $2::VARCHAR[], $3::VARCHAR[], $4::NUMERIC[], $5::VARCHAR[], $6::NUMERIC[], $7::NUMERIC[], $8::VARCHAR[])
+    ON CONFLICT DO NOTHING;
+    "#;
+    let ids : Vec<&str> = quotes.iter().map(|q| q.id.as_str()).collect();
+    let symbols : Vec<&str> = quotes.iter().map(|q| q.symbol.as_str()).collect();
+    let names : Vec<&str> = quotes.iter().map(|q| q.name.as_str()).collect();
+    let prices : Vec<Numeric> = quotes.iter().map(|q| q.current_price.unwrap_or_default()).collect();
+    let images : Vec<Option<&str>> = quotes.iter().map(|q| q.image.as_deref()).collect();
+    let market_caps : Vec<Numeric> = quotes.iter().map(|q| q.market_cap.unwrap_or_default().round_dp(4)).collect();
+    let volumes : Vec<Numeric> = quotes.iter().map(|q| q.total_volume.unwrap_or_default().round()).collect();
+    let now = chrono::Utc::now().to_rfc3339();
+    let timestamps : Vec<&str> = quotes.iter().map(|q| q.last_updated.as_deref().unwrap_or(&now)).collect();
+    Ok(client.execute(query, &[&ids, &symbols, &names, &prices, &images, &market_caps, &volumes, &timestamps]).await?)
+  }
+
+  /// Sells `size` of a coin at the latest price. The fill and the remaining position are read back in the same transaction
+  /// as the sale, while `sell_currency` still holds its lock on the transactions table.
+  pub async fn sell_currency<S : AsRef<str>>(&self, crypto_id : &S, size : TradeSize, user_id : &S, server_id : Option<&str>) -> BrokerResult<TradeReceipt> {
+    let (qty, amount) = BrokerMapper::trade_size_params(size);
+    let mut client = get_client!(self);
+    let tx = client.transaction().await?;
+    let transaction_id : i32 = tx.query_one(
+      "SELECT sell_currency($1,$2,$3,$4,make_interval(secs => $5),NULL,$6,trade_fees($4,$7,$8,$9))",
+      &[&qty,&crypto_id.as_ref(),&user_id.as_ref(),&server_id.unwrap_or(GLOBAL_WALLET),&self.max_quote_age_secs(),&amount,
+        &self.fees.commission_pct,&self.fees.min_fee,&self.fees.spread_pct]
+    ).await?.try_get(0)?;
+    let receipt = BrokerMapper::trade_receipt(&tx, transaction_id).await?;
+    tx.commit().await?;
+    Ok(receipt)
+  }
+
+  /// Records a quote at `price`, less the wallet's spread, executable for `ttl_secs`
+  pub async fn create_quote(&self, request : &PlaceQuoteRequest, crypto_id : &str, price : &Numeric, ttl_secs : i64) -> BrokerResult<QuoteRecord> {
+    let client = get_client!(self);
+    let query = r#"
+    INSERT INTO quotes (userId, serverId, cryptoId, side, qty, price, expiresAt)
+    VALUES ($1, $2, $3, $4, $5, trade_price($6, $4, trade_fees($2, $8, $9, $10)), NOW()::TIMESTAMP + make_interval(secs => $7))
+    RETURNING quoteId, userId, serverId, cryptoId, side, qty, price, expiresAt;
+    "#;
+    let row = client.query_one(query, &[
+      &request.user_id, &request.server_id.as_deref().unwrap_or(GLOBAL_WALLET), &crypto_id, &request.side.indicator(),
+      &request.qty, price, &(ttl_secs as f64), &self.fees.commission_pct, &self.fees.min_fee, &self.fees.spread_pct
+    ]).await?;
+    Ok(QuoteRecord::try_from(&row)?)
+  }
+
+  pub async fn quote_by_id(&self, quote_id : i32) -> BrokerResult<QuoteRecord> {
+    let client = get_client!(self);
+    let query = "SELECT quoteId, userId, serverId, cryptoId, side, qty, price, expiresAt FROM quotes WHERE quoteId = $1;";
+    match client.query_opt(query, &[&quote_id]).await? {
+      Some(row) => Ok(QuoteRecord::try_from(&row)?),
+      None => Err(BrokerError::NotFound(format!("Could not find quote {}", quote_id)))
+    }
+  }
+
+  /// Trades a quote at its price. Fails with `quote_expired` / `quote_used` once it can't be executed any more
+  pub async fn execute_quote(&self, quote : &QuoteRecord, user_id : &str, side : OrderSide) -> BrokerResult<TradeReceipt> {
+    let mut client = get_client!(self);
+    let tx = client.transaction().await?;
+    let transaction_id : i32 = tx.query_one(
+      "SELECT execute_quote($1,$2,$3,trade_fees($4,$5,$6,$7))",
+      &[&quote.quote_id, &user_id, &side.indicator(), &quote.server_id, &self.fees.commission_pct, &self.fees.min_fee, &self.fees.spread_pct]
+    ).await?.try_get(0)?;
+    let receipt = BrokerMapper::trade_receipt(&tx, transaction_id).await?;
+    tx.commit().await?;
+    Ok(receipt)
   }
   
-  pub async fn get_portfolio<S : AsRef<str>>(&self, user_id : &S) -> StdResult<Portfolio> {
-    let conf = self.config.clone();
-    let client : Client = get_client!(conf);
-    let balance = self.get_wallet_balance_by_userid(user_id).await?;
-    let positions : Vec<Position> = client.query("SELECT name,cryptoId,currentValue,qty FROM vPortfolio where userId = $1", &[&user_id.as_ref()]).await?.iter().map(|r| Position::try_from(r).expect("Could not create position")).collect();
-    Ok(Portfolio{balance,positions})
+  /// Reads the held positions from `vPortfolio` and works out their cost basis and P&L by replaying the user's ledger.
+  pub async fn get_portfolio<S : AsRef<str>>(&self, user_id : &S, server_id : Option<&str>, method : CostBasisMethod) -> BrokerResult<Portfolio> {
+    let server_id = server_id.unwrap_or(GLOBAL_WALLET);
+    let client = get_client!(self);
+    let balance = BrokerMapper::wallet_balance(&client, user_id.as_ref(), server_id).await?;
+    let positions : Vec<Position> = client.query("SELECT name,cryptoId,currentValue,qty FROM vPortfolio where userId = $1 AND serverId = $2", &[&user_id.as_ref(), &server_id]).await?.iter().map(Position::try_from).collect::<Result<Vec<Position>,_>>()?;
+    // fees count towards what a buy cost, and come out of what a sale raised
+    let rows = client.query(
+      "SELECT cryptoId, qty, cost + fee AS cost, fee FROM transactions WHERE userId = $1 AND serverId = $2 ORDER BY transactionId",
+      &[&user_id.as_ref(), &server_id]
+    ).await?;
+    let fees = rows.iter()
+      .map(|row| row.try_get::<&str,Numeric>("fee"))
+      .sum::<Result<Numeric,tokio_postgres::Error>>()?;
+    let ledger = rows.iter()
+      .map(|row| Ok((row.try_get("cryptoId")?, row.try_get("qty")?, row.try_get("cost")?)))
+      .collect::<Result<Vec<(String,Numeric,Numeric)>,tokio_postgres::Error>>()?;
+    let bases = cost_basis(method, ledger);
+    let positions : Vec<Position> = positions.into_iter()
+      .map(|p| match bases.get(&p.crypto_id) {
+        Some(basis) => p.with_cost_basis(basis),
+        None => p
+      })
+      .collect();
+    let mut totals = PortfolioTotals::default();
+    for p in positions.iter() {
+      totals.current_value += p.current_value;
+      totals.total_cost += p.total_cost;
+    }
+    totals.unrealized_pnl = totals.current_value - totals.total_cost;
+    totals.unrealized_pnl_pct = pnl_pct(totals.unrealized_pnl, totals.total_cost);
+    totals.realized_pnl = bases.values().map(|b| b.realized_pnl).sum();
+    totals.fees = fees;
+    Ok(Portfolio{balance,positions,totals})
   }
   
-  pub async fn update_server_patrons<S : AsRef<str>>(&self, user_ids : &Vec<String>, server_id : &S) -> StdResult<()> {
-    let conf = self.config.clone();
-    let client = get_client!(conf);
+  /// Ranks the patrons of a server. Users without a wallet don't appear, since they have nothing to rank.
+  /// Patrons are ranked on their global wallets unless `server_wallets` is set, in which case the wallets scoped to this server are used.
+  pub async fn leaderboard<S : AsRef<str>>(&self, server_id : &S, server_wallets : bool, sort_by : LeaderboardSort, limit : i64, offset : i64) -> BrokerResult<Vec<LeaderboardEntry>> {
+    let wallet_server_id = if server_wallets { server_id.as_ref() } else { GLOBAL_WALLET };
+    let sort_column = match sort_by {
+      LeaderboardSort::Balance => "balance",
+      LeaderboardSort::Networth => "netWorth",
+      LeaderboardSort::Roi => "roi"
+    };
+    let query = format!(r#"
+    WITH cteInvested AS (
+      SELECT
+        userId,
+        SUM(cost + fee) AS netCost,
+        SUM(CASE WHEN cost > 0 THEN cost + fee ELSE 0 END) AS totalBought
+      FROM transactions
+      WHERE serverId = $4
+      GROUP BY userId
+    ),
+    cteEntries AS (
+      SELECT
+        sp.userId,
+        w.walletBalance AS balance,
+        n.portfolioValue,
+        n.netWorth,
+        CASE WHEN i.totalBought > 0
+          THEN (n.portfolioValue - i.netCost) / i.totalBought
+          ELSE 0
+        END AS roi
+      FROM serverpatrons sp
+      JOIN wallet w ON w.userId = sp.userId AND w.serverId = $4
+      JOIN vNetworth n ON n.userId = sp.userId AND n.serverId = $4
+      LEFT JOIN cteInvested i ON i.userId = sp.userId
+      WHERE sp.serverId = $1
+    )
+    SELECT *, RANK() OVER (ORDER BY {0} DESC) AS rank
+    FROM cteEntries
+    ORDER BY {0} DESC, userId
+    LIMIT $2 OFFSET $3;
+    "#, sort_column);
+    let client = get_client!(self);
+    Ok(
+      client.query(query.as_str(), &[&server_id.as_ref(), &limit, &offset, &wallet_server_id]).await?
+      .iter()
+      .map(LeaderboardEntry::try_from)
+      .collect::<Result<Vec<LeaderboardEntry>,_>>()?
+    )
+  }
+
+  /// Places a limit order through `place_limit_order`, which reserves the cash or coins it needs.
+  pub async fn place_limit_order(&self, request : &PlaceOrderRequest, crypto_id : &str) -> BrokerResult<Order> {
+    let client = get_client!(self);
+    // expiry is computed against the database clock, the same one that stamps cryptodata.asOf
+    let expires_in = request.expires_in.map(|secs| secs as f64);
+    let order_id : i32 = client.query_one(
+      "SELECT place_limit_order($1,$2,$3,$4,$5,$6,NOW()::TIMESTAMP + make_interval(secs => $7),trade_fees($2,$8,$9,$10)) AS orderId",
+      &[&request.user_id, &request.server_id.as_deref().unwrap_or(GLOBAL_WALLET), &crypto_id, &request.side.indicator(), &request.qty, &request.limit_price, &expires_in,
+        &self.fees.commission_pct, &self.fees.min_fee, &self.fees.spread_pct]
+    ).await?.try_get("orderId")?;
+    BrokerMapper::order_by_id(&client, order_id).await
+  }
+
+  pub async fn list_orders(&self, user_id : &str, server_id : Option<&str>, status : Option<&str>) -> BrokerResult<Vec<Order>> {
+    let client = get_client!(self);
+    let query = r#"
+    SELECT * FROM orders
+    WHERE userId = $1 AND serverId = $2 AND ($3::VARCHAR IS NULL OR status = $3)
+    ORDER BY orderId DESC;
+    "#;
+    Ok(
+      client.query(query, &[&user_id, &server_id.unwrap_or(GLOBAL_WALLET), &status]).await?
+      .iter()
+      .map(Order::try_from)
+      .collect::<Result<Vec<Order>,_>>()?
+    )
+  }
+
+  /// Cancels one of the user's open orders, releasing whatever it reserved.
+  pub async fn cancel_order(&self, order_id : i32, user_id : &str) -> BrokerResult<Order> {
+    let client = get_client!(self);
+    let cancelled = client.query_opt(
+      "SELECT cancel_limit_order(orderId, 'cancelled') AS cancelled FROM orders WHERE orderId = $1 AND userId = $2",
+      &[&order_id, &user_id]
+    ).await?;
+    match cancelled {
+      None => Err(BrokerError::NotFound(format!("No order {} found for user {}", order_id, user_id))),
+      Some(row) if !row.try_get::<&str,bool>("cancelled")? => Err(BrokerError::Conflict(format!("Order {} is no longer open", order_id))),
+      Some(_) => BrokerMapper::order_by_id(&client, order_id).await
+    }
+  }
+
+  /// Expires stale orders and fills any whose limit was crossed by a newer price tick. Returns the number of fills.
+  pub async fn match_limit_orders(&self) -> BrokerResult<i32> {
+    let client = get_client!(self);
+    Ok(client.query_one(
+      "SELECT match_limit_orders($1,$2,$3) AS filled",
+      &[&self.fees.commission_pct, &self.fees.min_fee, &self.fees.spread_pct]
+    ).await?.try_get("filled")?)
+  }
+
+  async fn order_by_id(client : &Client, order_id : i32) -> BrokerResult<Order> {
+    Ok(Order::try_from(&client.query_one("SELECT * FROM orders WHERE orderId = $1", &[&order_id]).await?)?)
+  }
+
+  /// Attaches a stop-loss / take-profit to a position through `place_price_trigger`.
+  pub async fn place_price_trigger(&self, request : &PlaceTriggerRequest, crypto_id : &str) -> BrokerResult<PriceTrigger> {
+    let client = get_client!(self);
+    let trigger_id : i32 = client.query_one(
+      "SELECT place_price_trigger($1,$2,$3,$4,$5,$6) AS triggerId",
+      &[&request.user_id, &request.server_id.as_deref().unwrap_or(GLOBAL_WALLET), &crypto_id, &request.kind.as_str(), &request.trigger_price, &request.qty.qty()]
+    ).await?.try_get("triggerId")?;
+    BrokerMapper::price_trigger_by_id(&client, trigger_id).await
+  }
+
+  pub async fn list_price_triggers(&self, user_id : &str, server_id : Option<&str>, status : Option<&str>) -> BrokerResult<Vec<PriceTrigger>> {
+    let client = get_client!(self);
+    let query = r#"
+    SELECT * FROM pricetriggers
+    WHERE userId = $1 AND serverId = $2 AND ($3::VARCHAR IS NULL OR status = $3)
+    ORDER BY triggerId DESC;
+    "#;
+    Ok(
+      client.query(query, &[&user_id, &server_id.unwrap_or(GLOBAL_WALLET), &status]).await?
+      .iter()
+      .map(PriceTrigger::try_from)
+      .collect::<Result<Vec<PriceTrigger>,_>>()?
+    )
+  }
+
+  pub async fn cancel_price_trigger(&self, trigger_id : i32, user_id : &str) -> BrokerResult<PriceTrigger> {
+    let client = get_client!(self);
+    let cancelled = client.query_opt(r#"
+    UPDATE pricetriggers SET status = 'cancelled', closedAt = NOW(), closeReason = 'Cancelled by user'
+    WHERE triggerId = $1 AND userId = $2 AND status = 'active'
+    RETURNING *;
+    "#, &[&trigger_id, &user_id]).await?;
+    if let Some(row) = cancelled {
+      return Ok(PriceTrigger::try_from(&row)?);
+    }
+    match client.query_opt("SELECT 1 FROM pricetriggers WHERE triggerId = $1 AND userId = $2", &[&trigger_id, &user_id]).await? {
+      Some(_) => Err(BrokerError::Conflict(format!("Trigger {} is no longer active", trigger_id))),
+      None => Err(BrokerError::NotFound(format!("No trigger {} found for user {}", trigger_id, user_id)))
+    }
+  }
+
+  /// Cancels triggers on closed positions and sells positions whose price crossed a trigger. Returns the number fired.
+  pub async fn fire_price_triggers(&self) -> BrokerResult<i32> {
+    let client = get_client!(self);
+    Ok(client.query_one(
+      "SELECT fire_price_triggers($1,$2,$3) AS fired",
+      &[&self.fees.commission_pct, &self.fees.min_fee, &self.fees.spread_pct]
+    ).await?.try_get("fired")?)
+  }
+
+  async fn price_trigger_by_id(client : &Client, trigger_id : i32) -> BrokerResult<PriceTrigger> {
+    Ok(PriceTrigger::try_from(&client.query_one("SELECT * FROM pricetriggers WHERE triggerId = $1", &[&trigger_id]).await?)?)
+  }
+
+  /// Pages through a user's ledger newest first. Pages are keyed on `transactionId`, so rows written while paging don't
+  /// shift later pages.
+  pub async fn list_transactions(&self, request : &GetTransactionsRequest, limit : i64) -> BrokerResult<TransactionPage> {
+    let client = get_client!(self);
+    let query = r#"
+    SELECT
+      transactionId,
+      transactionTime,
+      userId,
+      serverId,
+      cryptoId,
+      buySellIndicator,
+      ABS(qty) AS qty,
+      ABS(cost + fee) AS total,
+      COALESCE(price, cost / qty) AS price,
+      fee
+    FROM transactions
+    WHERE userId = $1 AND serverId = $2
+      AND ($3::VARCHAR IS NULL OR cryptoId = $3)
+      AND ($4::VARCHAR IS NULL OR buySellIndicator = $4)
+      AND ($5::TIMESTAMP IS NULL OR transactionTime >= $5)
+      AND ($6::TIMESTAMP IS NULL OR transactionTime < $6)
+      AND ($7::INT IS NULL OR transactionId < $7)
+    ORDER BY transactionId DESC
+    LIMIT $8;
+    "#;
+    let side = request.side.map(|side| side.indicator());
+    let from = request.from.map(|ts| ts.naive_utc());
+    let to = request.to.map(|ts| ts.naive_utc());
+    // one extra row tells us whether there is another page
+    let mut transactions = client.query(query, &[
+      &request.user_id, &request.server_id.as_deref().unwrap_or(GLOBAL_WALLET), &request.crypto_id, &side, &from, &to, &request.cursor, &(limit + 1)
+    ]).await?
+      .iter()
+      .map(Transaction::try_from)
+      .collect::<Result<Vec<Transaction>,_>>()?;
+    let next_cursor = if transactions.len() as i64 > limit {
+      transactions.truncate(limit as usize);
+      transactions.last().map(|t| t.transaction_id)
+    } else {
+      None
+    };
+    Ok(TransactionPage { transactions, next_cursor })
+  }
+
+  /// The fees trades from `server_id`'s wallets pay: its `serverfees` row, falling back to the configured defaults
+  pub async fn trade_fees(&self, server_id : Option<&str>) -> BrokerResult<TradeFees> {
+    let server_id = server_id.unwrap_or(GLOBAL_WALLET);
+    let client = get_client!(self);
+    let row = client.query_one(r#"
+    SELECT f.commissionPct, f.minFee, f.spreadPct, EXISTS (SELECT 1 FROM serverfees s WHERE s.serverId = $1) AS overridden
+    FROM trade_fees($1, $2, $3, $4) f;
+    "#, &[&server_id, &self.fees.commission_pct, &self.fees.min_fee, &self.fees.spread_pct]).await?;
+    let mut fees = TradeFees::try_from(&row)?;
+    fees.server_id = Some(server_id.to_string()).filter(|s| s != GLOBAL_WALLET);
+    Ok(fees)
+  }
+
+  /// Replaces a server's fee override, or removes it when the request sets no fees
+  pub async fn set_server_fees(&self, request : &UpdateFeesRequest) -> BrokerResult<TradeFees> {
+    {
+      let client = get_client!(self);
+      if request.commission_pct.is_none() && request.min_fee.is_none() && request.spread_pct.is_none() {
+        client.execute("DELETE FROM serverfees WHERE serverId = $1;", &[&request.server_id]).await?;
+      } else {
+        client.execute(r#"
+        INSERT INTO serverfees (serverId, commissionPct, minFee, spreadPct)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (serverId)
+        DO
+          UPDATE SET commissionPct = EXCLUDED.commissionPct, minFee = EXCLUDED.minFee, spreadPct = EXCLUDED.spreadPct;
+        "#, &[&request.server_id, &request.commission_pct, &request.min_fee, &request.spread_pct]).await?;
+      }
+    }
+    self.trade_fees(Some(&request.server_id)).await
+  }
+
+  pub async fn update_server_patrons<S : AsRef<str>>(&self, user_ids : &[String], server_id : &S) -> BrokerResult<()> {
+    let client = get_client!(self);
     // update_server_patrons(user_ids, server_id.as_ref(), &client).await
     let query = r#"
     INSERT INTO
@@ -212,7 +936,8 @@ impl TryFrom<&Row> for CurrencyData {
       image_url : row.try_get("image_url")?,
       market_cap : row.try_get("market_cap")?,
       volume: row.try_get("volume")?,
-      coingecko_timestamp: row.try_get("coingecko_timestamp")?
+      coingecko_timestamp: row.try_get("coingecko_timestamp")?,
+      stale : false
     })
   }
 }
@@ -224,7 +949,273 @@ impl TryFrom<&Row> for Position {
       name : row.try_get("name")?,
       crypto_id : row.try_get("cryptoId")?,
       current_value : row.try_get("currentValue")?,
-      qty: row.try_get("qty")?
+      qty: row.try_get("qty")?,
+      average_cost : Numeric::ZERO,
+      total_cost : Numeric::ZERO,
+      unrealized_pnl : Numeric::ZERO,
+      unrealized_pnl_pct : Numeric::ZERO,
+      realized_pnl : Numeric::ZERO
+    })
+  }
+}
+
+
+impl TryFrom<&Row> for LeaderboardEntry {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<LeaderboardEntry,Self::Error> {
+    Ok(LeaderboardEntry{
+      rank : row.try_get("rank")?,
+      user_id : row.try_get("userId")?,
+      balance : row.try_get("balance")?,
+      portfolio_value : row.try_get("portfolioValue")?,
+      net_worth : row.try_get("netWorth")?,
+      roi : row.try_get("roi")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for Order {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<Order,Self::Error> {
+    let server_id : String = row.try_get("serverId")?;
+    let to_utc = |ts : Option<chrono::NaiveDateTime>| ts.map(|ts| chrono::DateTime::from_utc(ts, chrono::Utc));
+    Ok(Order{
+      order_id : row.try_get("orderId")?,
+      user_id : row.try_get("userId")?,
+      server_id : Some(server_id).filter(|s| s != GLOBAL_WALLET),
+      crypto_id : row.try_get("cryptoId")?,
+      side : OrderSide::from_indicator(row.try_get("side")?),
+      qty : row.try_get("qty")?,
+      limit_price : row.try_get("limitPrice")?,
+      reserved : row.try_get("reserved")?,
+      status : row.try_get("status")?,
+      created_at : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("createdAt")?, chrono::Utc),
+      expires_at : to_utc(row.try_get("expiresAt")?),
+      closed_at : to_utc(row.try_get("closedAt")?),
+      fill_price : row.try_get("fillPrice")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for PriceTrigger {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<PriceTrigger,Self::Error> {
+    let server_id : String = row.try_get("serverId")?;
+    let qty : Option<Numeric> = row.try_get("qty")?;
+    Ok(PriceTrigger{
+      trigger_id : row.try_get("triggerId")?,
+      user_id : row.try_get("userId")?,
+      server_id : Some(server_id).filter(|s| s != GLOBAL_WALLET),
+      crypto_id : row.try_get("cryptoId")?,
+      kind : TriggerKind::from_db(row.try_get("kind")?),
+      trigger_price : row.try_get("triggerPrice")?,
+      qty : qty.map_or(PositionQty::All, PositionQty::Qty),
+      status : row.try_get("status")?,
+      created_at : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("createdAt")?, chrono::Utc),
+      closed_at : row.try_get::<&str,Option<chrono::NaiveDateTime>>("closedAt")?.map(|ts| chrono::DateTime::from_utc(ts, chrono::Utc)),
+      fired_price : row.try_get("firedPrice")?,
+      transaction_id : row.try_get("transactionId")?,
+      close_reason : row.try_get("closeReason")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for Transaction {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<Transaction,Self::Error> {
+    let server_id : String = row.try_get("serverId")?;
+    Ok(Transaction{
+      transaction_id : row.try_get("transactionId")?,
+      transaction_time : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("transactionTime")?, chrono::Utc),
+      user_id : row.try_get("userId")?,
+      server_id : Some(server_id).filter(|s| s != GLOBAL_WALLET),
+      crypto_id : row.try_get("cryptoId")?,
+      side : OrderSide::from_indicator(row.try_get("buySellIndicator")?),
+      qty : row.try_get("qty")?,
+      price : row.try_get("price")?,
+      total : row.try_get("total")?,
+      fee : row.try_get("fee")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for PricePoint {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<PricePoint,Self::Error> {
+    Ok(PricePoint{
+      as_of : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("asOf")?, chrono::Utc),
+      price : row.try_get("price")?,
+      market_cap : row.try_get("market_cap")?,
+      volume : row.try_get("volume")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for Candle {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<Candle,Self::Error> {
+    Ok(Candle{
+      open_time : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("openTime")?, chrono::Utc),
+      open : row.try_get("open")?,
+      high : row.try_get("high")?,
+      low : row.try_get("low")?,
+      close : row.try_get("close")?,
+      volume : row.try_get("volume")?,
+      samples : row.try_get("samples")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for NetWorthPoint {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<NetWorthPoint,Self::Error> {
+    Ok(NetWorthPoint{
+      as_of : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("asOf")?, chrono::Utc),
+      net_worth : row.try_get("netWorth")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for NetWorthSnapshot {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<NetWorthSnapshot,Self::Error> {
+    Ok(NetWorthSnapshot{
+      as_of : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("snapshotAt")?, chrono::Utc),
+      cash : row.try_get("cash")?,
+      portfolio_value : row.try_get("portfolioValue")?,
+      net_worth : row.try_get("netWorth")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for QuoteRecord {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<QuoteRecord,Self::Error> {
+    Ok(QuoteRecord{
+      quote_id : row.try_get("quoteId")?,
+      user_id : row.try_get("userId")?,
+      server_id : row.try_get("serverId")?,
+      crypto_id : row.try_get("cryptoId")?,
+      side : OrderSide::from_indicator(row.try_get("side")?),
+      qty : row.try_get("qty")?,
+      price : row.try_get("price")?,
+      expires_at : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("expiresAt")?, chrono::Utc)
+    })
+  }
+}
+
+impl TryFrom<&Row> for TradeFees {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<TradeFees,Self::Error> {
+    Ok(TradeFees{
+      server_id : None,
+      commission_pct : row.try_get("commissionPct")?,
+      min_fee : row.try_get("minFee")?,
+      spread_pct : row.try_get("spreadPct")?,
+      overridden : row.try_get("overridden")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for ApiKeyRecord {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<ApiKeyRecord,Self::Error> {
+    Ok(ApiKeyRecord{
+      id : row.try_get("id")?,
+      salt : row.try_get("salt")?,
+      key_hash : row.try_get("keyHash")?,
+      // scopes are constrained by the table, so an unknown one can only come from a newer schema and grants nothing
+      scopes : row.try_get::<&str,Vec<&str>>("scopes")?.into_iter().filter_map(Scope::from_db).collect(),
+      expires_at : row.try_get::<&str,Option<chrono::NaiveDateTime>>("expiresAt")?.map(|ts| chrono::DateTime::from_utc(ts, chrono::Utc)),
+      read_limit : row.try_get::<&str,Option<i32>>("readLimit")?.map(|l| l as u32),
+      trade_limit : row.try_get::<&str,Option<i32>>("tradeLimit")?.map(|l| l as u32),
+      signing_secret : row.try_get("signingSecret")?,
+      legacy : row.try_get("legacy")?
+    })
+  }
+}
+
+impl TryFrom<&Row> for ApiKeyInfo {
+  type Error = tokio_postgres::error::Error;
+  fn try_from(row : &Row) -> Result<ApiKeyInfo,Self::Error> {
+    let to_utc = |ts : Option<chrono::NaiveDateTime>| ts.map(|ts| chrono::DateTime::from_utc(ts, chrono::Utc));
+    Ok(ApiKeyInfo{
+      key_id : row.try_get("id")?,
+      description : row.try_get("description")?,
+      scopes : row.try_get::<&str,Vec<&str>>("scopes")?.into_iter().filter_map(Scope::from_db).collect(),
+      read_limit : row.try_get::<&str,Option<i32>>("readLimit")?.map(|l| l as u32),
+      trade_limit : row.try_get::<&str,Option<i32>>("tradeLimit")?.map(|l| l as u32),
+      signed : row.try_get("signed")?,
+      legacy : row.try_get("legacy")?,
+      status : row.try_get("status")?,
+      created_at : chrono::DateTime::from_utc(row.try_get::<&str,chrono::NaiveDateTime>("createdAt")?, chrono::Utc),
+      last_used_at : to_utc(row.try_get("lastUsedAt")?),
+      expires_at : to_utc(row.try_get("expiresAt")?),
+      revoked_at : to_utc(row.try_get("revokedAt")?)
     })
   }
 }
+
+// These run against the database named by the `CB_DB*` environment variables, which needs schema.sql loaded, and
+// also need `CB_QUOTE_SECRET`: `cargo test -- --ignored`. CI runs them against a Postgres service (.github/workflows/test.yml).
+// Each test makes its own coin and user, so they can share a database
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn mapper() -> BrokerMapper {
+    dotenv::dotenv().ok();
+    let config = crate::config::load_config();
+    BrokerMapper::new(&config.data_source, &config.pool, &config.quotes, &config.fees)
+  }
+
+  /// A fresh coin priced at 10 and a user holding 2 of it, returned as `(coin, user)`
+  async fn holder_of_two(mapper : &BrokerMapper, name : &str) -> (String, String) {
+    let suffix = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+    let (coin, user) = (format!("test-{}-{}", name, suffix), format!("test-{}-{}", name, suffix));
+    let client = mapper.pool.get().await.unwrap();
+    client.execute(
+      "INSERT INTO cryptodata (id, symbol, name, price, market_cap, volume, coingecko_timestamp) VALUES ($1, 'tst', $1, 10, 1, 1, '')", &[&coin]
+    ).await.unwrap();
+    client.execute("INSERT INTO wallet (userId, serverId, walletBalance) VALUES ($1, '', 1000)", &[&user]).await.unwrap();
+    mapper.buy_currency(&coin, TradeSize::Qty(Numeric::from(2)), &user, None).await.unwrap();
+    (coin, user)
+  }
+
+  /// Balance and number of transactions, which a refused sell mustn't change
+  async fn wallet_state(mapper : &BrokerMapper, user : &str) -> (Numeric, i64) {
+    let balance = mapper.get_wallet_balance_by_userid(&user, None).await.unwrap();
+    let client = mapper.pool.get().await.unwrap();
+    let count : i64 = client.query_one("SELECT COUNT(*) FROM transactions WHERE userId = $1", &[&user]).await.unwrap().get(0);
+    (balance, count)
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a database"]
+  async fn selling_more_than_held_is_refused() {
+    let mapper = mapper().await;
+    let (coin, user) = holder_of_two(&mapper, "oversell").await;
+    let before = wallet_state(&mapper, &user).await;
+
+    let err = mapper.sell_currency(&coin, TradeSize::Qty(Numeric::from(3)), &user, None).await.unwrap_err();
+    assert_eq!(err.code(), "insufficient_funds");
+    assert_eq!(wallet_state(&mapper, &user).await, before);
+  }
+
+  #[tokio::test]
+  #[ignore = "needs a database"]
+  async fn selling_what_open_sell_orders_reserve_is_refused() {
+    let mapper = mapper().await;
+    let (coin, user) = holder_of_two(&mapper, "reserved").await;
+    let order : PlaceOrderRequest = serde_json::from_value(serde_json::json!({
+      "userId" : user, "side" : "sell", "qty" : 1.5, "limitPrice" : 1000, "cryptoId" : coin
+    })).unwrap();
+    mapper.place_limit_order(&order, &coin).await.unwrap();
+    let before = wallet_state(&mapper, &user).await;
+
+    // 2 held, but only 0.5 isn't reserved by the order
+    let err = mapper.sell_currency(&coin, TradeSize::Qty(Numeric::from(1)), &user, None).await.unwrap_err();
+    assert_eq!(err.code(), "insufficient_funds");
+    assert_eq!(wallet_state(&mapper, &user).await, before);
+  }
+}
@@ -0,0 +1,48 @@
+//! Signing of the quote ids handed out by `POST /quotes`, so clients can't guess or alter them
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::errors::{BrokerError, BrokerResult};
+use crate::types::QuoteRecord;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct QuoteSigner {
+    key : Vec<u8>
+}
+
+impl QuoteSigner {
+    pub fn new(secret : &str) -> QuoteSigner {
+        QuoteSigner { key : secret.as_bytes().to_vec() }
+    }
+
+    /// MAC over every term of the quote, so changing any of them in the database also invalidates the id
+    fn mac(&self, quote : &QuoteRecord) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(format!(
+            "{}|{}|{}|{}|{}|{}|{}|{}",
+            quote.quote_id, quote.user_id, quote.server_id, quote.crypto_id, quote.side.indicator(),
+            quote.qty.normalize(), quote.price.normalize(), quote.expires_at.timestamp()
+        ).as_bytes());
+        mac
+    }
+
+    /// `<id>.<hex HMAC-SHA256 of the quote>`
+    pub fn quote_id(&self, quote : &QuoteRecord) -> String {
+        format!("{}.{}", quote.quote_id, hex::encode(self.mac(quote).finalize().into_bytes()))
+    }
+
+    /// Splits a quote id into the `quotes` row it names and its signature, which still has to be checked with `verify`
+    pub fn parse(quote_id : &str) -> BrokerResult<(i32, Vec<u8>)> {
+        let invalid = || BrokerError::Validation(String::from("quoteId is not valid"));
+        let (id, signature) = quote_id.split_once('.').ok_or_else(invalid)?;
+        Ok((id.parse().map_err(|_| invalid())?, hex::decode(signature).map_err(|_| invalid())?))
+    }
+
+    /// Checks `signature` against `quote` in constant time
+    pub fn verify(&self, quote : &QuoteRecord, signature : &[u8]) -> BrokerResult<()> {
+        self.mac(quote).verify_slice(signature)
+            .map_err(|_| BrokerError::Validation(String::from("quoteId is not valid")))
+    }
+}
@@ -0,0 +1,217 @@
+//! Token buckets behind `middlewares::ratelimit`, one per API key and one per user for each class of route
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use crate::config::RateLimitConfig;
+
+/// Which budget a request draws on
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum RouteClass {
+    /// `GET`s
+    Read,
+    /// Everything that changes something
+    Trade
+}
+
+impl RouteClass {
+    fn describe(&self) -> &'static str {
+        match self {
+            RouteClass::Read => "reads",
+            RouteClass::Trade => "trades"
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+enum BucketKey {
+    ApiKey(i32, RouteClass),
+    User(String, RouteClass)
+}
+
+struct Bucket {
+    tokens : f64,
+    /// The limit the bucket was last refilled up to, which can change when a key's own limit does
+    capacity : f64,
+    refilled_at : Instant
+}
+
+/// What is left in the emptiest bucket a request drew from, for the `X-RateLimit-*` headers
+#[derive(Clone,Copy,Debug)]
+pub struct RateLimitStatus {
+    pub limit : u32,
+    pub remaining : u32,
+    /// Seconds until the bucket is full again
+    pub reset_secs : u64
+}
+
+/// Why a request was turned away
+#[derive(Clone,Debug)]
+pub struct Throttled {
+    pub msg : String,
+    pub limit : u32,
+    /// Seconds until a request would be let through
+    pub retry_after_secs : u64,
+    pub reset_secs : u64
+}
+
+/// Once there are this many buckets, ones that have filled back up are dropped so idle keys and users don't pile up
+const PRUNE_AT : usize = 10_000;
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    config : RateLimitConfig,
+    buckets : Arc<Mutex<HashMap<BucketKey, Bucket>>>
+}
+
+impl RateLimiter {
+    pub fn new(config : RateLimitConfig) -> RateLimiter {
+        RateLimiter { config, buckets : Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Takes a token from the API key's bucket and the user's bucket for `class`, or from neither when either is
+    /// empty. `key` is the key's id and its own limit, if it has one. Returns `None` when no limit applies
+    pub fn check(&self, class : RouteClass, key : Option<(i32, Option<u32>)>, user_id : Option<&str>) -> Result<Option<RateLimitStatus>, Throttled> {
+        let window = self.config.window().as_secs_f64();
+        let mut limits = Vec::new();
+        if let Some((id, own_limit)) = key {
+            let default = match class { RouteClass::Read => self.config.key_read, RouteClass::Trade => self.config.key_trade };
+            limits.push((BucketKey::ApiKey(id, class), own_limit.unwrap_or(default), String::from("this API key")));
+        }
+        if let Some(user_id) = user_id {
+            let limit = match class { RouteClass::Read => self.config.user_read, RouteClass::Trade => self.config.user_trade };
+            limits.push((BucketKey::User(user_id.to_string(), class), limit, format!("user {}", user_id)));
+        }
+        limits.retain(|(_, limit, _)| *limit > 0);
+        if limits.is_empty() || window <= 0.0 {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= PRUNE_AT {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * bucket.capacity / window < bucket.capacity);
+        }
+        let secs_until = |tokens : f64, limit : f64| ((tokens * window / limit).ceil() as u64).max(1);
+
+        let mut throttled : Option<Throttled> = None;
+        for (key, limit, who) in &limits {
+            let capacity = *limit as f64;
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket { tokens : capacity, capacity, refilled_at : now });
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * capacity / window;
+            bucket.tokens = (bucket.tokens + refill).min(capacity);
+            bucket.capacity = capacity;
+            bucket.refilled_at = now;
+            if bucket.tokens < 1.0 {
+                let retry_after_secs = secs_until(1.0 - bucket.tokens, capacity);
+                if throttled.as_ref().is_none_or(|t| t.retry_after_secs < retry_after_secs) {
+                    throttled = Some(Throttled {
+                        msg : format!("Rate limit of {} {} per {}s reached for {}", limit, class.describe(), self.config.window_secs, who),
+                        limit : *limit,
+                        retry_after_secs,
+                        reset_secs : secs_until(capacity - bucket.tokens, capacity)
+                    });
+                }
+            }
+        }
+        if let Some(throttled) = throttled {
+            return Err(throttled);
+        }
+
+        let mut status : Option<RateLimitStatus> = None;
+        for (key, limit, _) in &limits {
+            let bucket = buckets.get_mut(key).expect("every bucket was just refilled");
+            bucket.tokens -= 1.0;
+            let remaining = bucket.tokens.floor() as u32;
+            if status.is_none_or(|s| remaining < s.remaining) {
+                status = Some(RateLimitStatus {
+                    limit : *limit,
+                    remaining,
+                    reset_secs : secs_until(bucket.capacity - bucket.tokens, bucket.capacity)
+                });
+            }
+        }
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(key_trade : u32, user_trade : u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig { window_secs : 60, key_read : 0, key_trade, user_read : 0, user_trade })
+    }
+
+    /// Moves every bucket's last refill `secs` into the past
+    fn wait(limiter : &RateLimiter, secs : u64) {
+        for bucket in limiter.buckets.lock().unwrap().values_mut() {
+            bucket.refilled_at -= Duration::from_secs(secs);
+        }
+    }
+
+    fn remaining(result : Result<Option<RateLimitStatus>, Throttled>) -> u32 {
+        result.ok().flatten().expect("the request was let through with a limit").remaining
+    }
+
+    #[test]
+    fn throttles_once_the_budget_is_spent() {
+        let limiter = limiter(3, 0);
+        for left in [2, 1, 0] {
+            assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, None)), None)), left);
+        }
+        let throttled = limiter.check(RouteClass::Trade, Some((1, None)), None).unwrap_err();
+        assert_eq!(throttled.limit, 3);
+        // a token comes back every 20s, and the whole budget in 60s
+        assert_eq!(throttled.retry_after_secs, 20);
+        assert_eq!(throttled.reset_secs, 60);
+        assert_eq!(throttled.msg, "Rate limit of 3 trades per 60s reached for this API key");
+        // other keys and classes have their own buckets
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((2, None)), None)), 2);
+        assert!(limiter.check(RouteClass::Read, Some((1, None)), None).unwrap().is_none());
+    }
+
+    #[test]
+    fn refills_over_the_window() {
+        let limiter = limiter(3, 0);
+        for _ in 0..3 {
+            limiter.check(RouteClass::Trade, Some((1, None)), None).unwrap();
+        }
+        wait(&limiter, 10);
+        assert_eq!(limiter.check(RouteClass::Trade, Some((1, None)), None).unwrap_err().retry_after_secs, 10);
+        wait(&limiter, 10);
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, None)), None)), 0);
+        assert!(limiter.check(RouteClass::Trade, Some((1, None)), None).is_err());
+        // a bucket never holds more than its limit
+        wait(&limiter, 600);
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, None)), None)), 2);
+    }
+
+    #[test]
+    fn applies_the_keys_own_limit() {
+        let limiter = limiter(3, 0);
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, Some(10))), None)), 9);
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, Some(1))), None)), 0);
+        assert_eq!(limiter.check(RouteClass::Trade, Some((1, Some(1))), None).unwrap_err().limit, 1);
+    }
+
+    #[test]
+    fn takes_from_neither_bucket_when_one_is_empty() {
+        let limiter = limiter(5, 1);
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, None)), Some("u1"))), 0);
+        let throttled = limiter.check(RouteClass::Trade, Some((1, None)), Some("u1")).unwrap_err();
+        assert_eq!(throttled.msg, "Rate limit of 1 trades per 60s reached for user u1");
+        assert_eq!(throttled.retry_after_secs, 60);
+        // the refused request left the key's bucket alone
+        assert_eq!(remaining(limiter.check(RouteClass::Trade, Some((1, None)), None)), 3);
+    }
+
+    #[test]
+    fn turned_off_budgets_let_everything_through() {
+        let limiter = limiter(0, 0);
+        for _ in 0..100 {
+            assert!(limiter.check(RouteClass::Trade, Some((1, None)), Some("u1")).unwrap().is_none());
+        }
+    }
+}
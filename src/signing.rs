@@ -0,0 +1,93 @@
+//! Signed requests, for keys with a `signingSecret`. A leaked `X-CB-API-KEY` header alone can't be replayed against
+//! them, since each request also carries an HMAC-SHA256 of itself that is only good once and only for a few minutes
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+pub const TIMESTAMP_HEADER : &str = "X-CB-TIMESTAMP";
+pub const NONCE_HEADER : &str = "X-CB-NONCE";
+pub const SIGNATURE_HEADER : &str = "X-CB-SIGNATURE";
+
+/// Once there are this many nonces, the ones too old to be accepted again are dropped
+const PRUNE_AT : usize = 10_000;
+
+/// What gets signed: the timestamp, nonce, method, path, query string and hex SHA-256 of the body, one per line
+pub fn string_to_sign(timestamp : &str, nonce : &str, method : &str, path : &str, query : &str, body : &[u8]) -> String {
+    format!("{}\n{}\n{}\n{}\n{}\n{}", timestamp, nonce, method, path, query, hex::encode(Sha256::digest(body)))
+}
+
+/// The nonces each key has used recently, shared by every worker. A nonce only has to be remembered until its
+/// timestamp falls out of the skew window, after which the timestamp check refuses it anyway
+#[derive(Clone)]
+pub struct NonceCache {
+    max_skew_secs : i64,
+    /// When each nonce can be forgotten
+    seen : Arc<Mutex<HashMap<(i32, String), i64>>>
+}
+
+impl NonceCache {
+    pub fn new(max_skew : std::time::Duration) -> NonceCache {
+        NonceCache { max_skew_secs : max_skew.as_secs() as i64, seen : Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Checks `timestamp`, in unix seconds, is within the skew window and that key `key_id` hasn't used `nonce` yet,
+    /// then remembers it. Only call this once the signature is known to be good, or anyone could burn a key's nonces
+    pub fn check(&self, key_id : i32, timestamp : i64, nonce : &str) -> Result<(), String> {
+        let now = Utc::now().timestamp();
+        if (now - timestamp).abs() > self.max_skew_secs {
+            return Err(format!("Request timestamp is more than {}s away from the server's clock", self.max_skew_secs));
+        }
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if seen.len() >= PRUNE_AT {
+            seen.retain(|_, forget_at| *forget_at >= now);
+        }
+        if seen.insert((key_id, nonce.to_string()), timestamp + self.max_skew_secs).is_some() {
+            return Err(String::from("Request nonce was already used"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn signs_every_part_of_the_request() {
+        assert_eq!(
+            string_to_sign("1760000000", "n1", "GET", "/balance", "userId=u1", b""),
+            "1760000000\nn1\nGET\n/balance\nuserId=u1\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            string_to_sign("1760000000", "n1", "POST", "/buy", "", b"abc"),
+            "1760000000\nn1\nPOST\n/buy\n\nba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn refuses_replayed_nonces() {
+        let nonces = NonceCache::new(Duration::from_secs(300));
+        let now = Utc::now().timestamp();
+        assert_eq!(nonces.check(1, now, "n1"), Ok(()));
+        assert_eq!(nonces.check(1, now, "n1"), Err(String::from("Request nonce was already used")));
+        assert_eq!(nonces.check(1, now - 10, "n1"), Err(String::from("Request nonce was already used")));
+        // nonces are per key
+        assert_eq!(nonces.check(2, now, "n1"), Ok(()));
+        assert_eq!(nonces.check(1, now, "n2"), Ok(()));
+    }
+
+    #[test]
+    fn refuses_timestamps_outside_the_skew_window() {
+        let nonces = NonceCache::new(Duration::from_secs(300));
+        let now = Utc::now().timestamp();
+        let refused = Err(String::from("Request timestamp is more than 300s away from the server's clock"));
+        assert_eq!(nonces.check(1, now - 400, "n1"), refused);
+        assert_eq!(nonces.check(1, now + 400, "n1"), refused);
+        // a refused request doesn't use up its nonce
+        assert_eq!(nonces.check(1, now - 250, "n1"), Ok(()));
+        assert_eq!(nonces.check(1, now + 250, "n2"), Ok(()));
+    }
+}
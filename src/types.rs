@@ -1,18 +1,13 @@
 use crate::BrokerMapper;
+use crate::config::Config;
+use crate::accounting::CostBasis;
+use crate::quotes::QuoteSigner;
 use serde;
-use serde::{Serialize};
+use serde::{Serialize,Deserialize};
 use chrono::{DateTime,Utc};
-#[allow(bare_trait_objects)]
-pub type StdError = std::error::Error;
-pub type StdResult<T> = Result<T,Box<dyn std::error::Error>>;
+use std::convert::TryFrom;
 pub type Numeric = rust_decimal::Decimal;
 
-#[inline(always)]
-pub fn new_std_err(msg : &str) -> Box<std::io::Error>{
-    use std::io::{Error,ErrorKind};
-    Box::new(Error::new(ErrorKind::Other,msg))
-}
-
 #[derive(Serialize,Clone,Debug)]
 pub struct StatusResponse {
   pub success : bool,
@@ -28,6 +23,27 @@ impl StatusResponse {
   }
 }
 
+/// Body of `GET /status`
+#[derive(Serialize,Clone,Debug)]
+pub struct ServiceStatus {
+  pub success : bool,
+  /// `asOf` of the newest row in `cryptodata`, missing when there are no prices at all
+  #[serde(rename = "newestQuoteAt", with = "optional_date_formatter", skip_serializing_if = "Option::is_none")]
+  pub newest_quote_at : Option<DateTime<Utc>>,
+  #[serde(rename = "newestQuoteAgeSecs", skip_serializing_if = "Option::is_none")]
+  pub newest_quote_age_secs : Option<i64>,
+  /// Missing when the stale price check is turned off
+  #[serde(rename = "maxQuoteAgeSecs", skip_serializing_if = "Option::is_none")]
+  pub max_quote_age_secs : Option<u64>,
+  /// Every price is too old to trade at, or there are none
+  #[serde(rename = "pricesStale")]
+  pub prices_stale : bool,
+  #[serde(rename = "ingestionEnabled")]
+  pub ingestion_enabled : bool,
+  #[serde(rename = "authMode")]
+  pub auth_mode : AuthMode
+}
+
 #[derive(Serialize,Clone,Debug)]
 pub struct CurrencyData {
   // #[serde(rename = "asOf")]
@@ -43,7 +59,9 @@ pub struct CurrencyData {
   pub market_cap : Numeric,
   pub volume : Numeric,
   #[serde(rename = "coingeckoTimestamp")]
-  pub coingecko_timestamp : String
+  pub coingecko_timestamp : String,
+  /// The price is older than the configured max quote age, so it can't be traded at
+  pub stale : bool
 }
 
 #[derive(Serialize,Clone)]
@@ -54,16 +72,719 @@ pub struct Position {
   #[serde(rename = "currentValue")]
   pub current_value : Numeric,
   pub qty : Numeric,
+  /// Cost per coin of the qty held
+  #[serde(rename = "averageCost")]
+  pub average_cost : Numeric,
+  /// What the qty held cost to buy
+  #[serde(rename = "totalCost")]
+  pub total_cost : Numeric,
+  /// `currentValue - totalCost`
+  #[serde(rename = "unrealizedPnl")]
+  pub unrealized_pnl : Numeric,
+  /// Unrealized P&L as a percent of `totalCost`
+  #[serde(rename = "unrealizedPnlPct")]
+  pub unrealized_pnl_pct : Numeric,
+  /// Profit already taken by selling part of this coin
+  #[serde(rename = "realizedPnl")]
+  pub realized_pnl : Numeric,
+}
+
+impl Position {
+  pub fn with_cost_basis(mut self, basis : &CostBasis) -> Position {
+    self.average_cost = basis.average_cost();
+    self.total_cost = basis.total_cost;
+    self.unrealized_pnl = self.current_value - basis.total_cost;
+    self.unrealized_pnl_pct = pnl_pct(self.unrealized_pnl, basis.total_cost);
+    self.realized_pnl = basis.realized_pnl;
+    self
+  }
+}
+
+/// `pnl` as a percent of `cost`, or 0 when nothing was paid
+pub fn pnl_pct(pnl : Numeric, cost : Numeric) -> Numeric {
+  if cost.is_zero() { Numeric::ZERO } else { (pnl / cost * Numeric::ONE_HUNDRED).round_dp(4) }
+}
+
+/// Sums across every position in a portfolio. `realizedPnl` also counts coins that have since been sold off entirely.
+#[derive(Serialize,Clone,Default)]
+pub struct PortfolioTotals {
+  #[serde(rename = "currentValue")]
+  pub current_value : Numeric,
+  #[serde(rename = "totalCost")]
+  pub total_cost : Numeric,
+  #[serde(rename = "unrealizedPnl")]
+  pub unrealized_pnl : Numeric,
+  #[serde(rename = "unrealizedPnlPct")]
+  pub unrealized_pnl_pct : Numeric,
+  #[serde(rename = "realizedPnl")]
+  pub realized_pnl : Numeric,
+  /// Commission paid on every trade from the wallet, already counted in the costs and P&L above
+  pub fees : Numeric
+}
+
+/// The result of a filled trade
+#[derive(Serialize,Clone,Debug)]
+pub struct TradeReceipt {
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub qty : Numeric,
+  /// Price per coin the trade filled at
+  pub price : Numeric,
+  /// Cash paid for a buy, or received for a sell, after the fee
+  pub total : Numeric,
+  /// Commission charged on the trade
+  pub fee : Numeric,
+  /// Qty of the coin still held after the trade
+  #[serde(rename = "remainingQty")]
+  pub remaining_qty : Numeric
 }
 
 #[derive(Serialize,Clone)]
 pub struct Portfolio {
   pub balance : Numeric,
-  pub positions : Vec<Position>
+  pub positions : Vec<Position>,
+  pub totals : PortfolioTotals
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct LeaderboardEntry {
+  pub rank : i64,
+  #[serde(rename = "userId")]
+  pub user_id : String,
+  /// Cash held in the wallet
+  pub balance : Numeric,
+  #[serde(rename = "portfolioValue")]
+  pub portfolio_value : Numeric,
+  #[serde(rename = "netWorth")]
+  pub net_worth : Numeric,
+  /// Return on everything the user has bought, as a fraction (0.1 is +10%)
+  pub roi : Numeric
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct Leaderboard {
+  #[serde(rename = "serverId")]
+  pub server_id : String,
+  pub entries : Vec<LeaderboardEntry>
+}
+
+/// A successful `/daily-reward` claim
+#[derive(Serialize,Clone,Debug)]
+pub struct DailyRewardClaim {
+  #[serde(rename = "userId")]
+  pub user_id : String,
+  #[serde(rename = "serverId", skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
+  pub amount : Numeric,
+  /// Consecutive UTC days claimed, including today
+  pub streak : i32,
+  /// Wallet balance after the reward was paid
+  pub balance : Numeric,
+  #[serde(with = "date_formatter", rename = "nextClaimAt")]
+  pub next_claim_at : DateTime<Utc>
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderSide {
+  #[serde(alias = "B", alias = "b")]
+  Buy,
+  #[serde(alias = "S", alias = "s")]
+  Sell
+}
+
+impl OrderSide {
+  /// The `B`/`S` indicator used by the orders and transactions tables
+  pub fn indicator(&self) -> &'static str {
+    match self {
+      OrderSide::Buy => "B",
+      OrderSide::Sell => "S"
+    }
+  }
+
+  pub fn from_indicator(indicator : &str) -> OrderSide {
+    if indicator == "B" { OrderSide::Buy } else { OrderSide::Sell }
+  }
+}
+
+/// A row of the `quotes` table
+#[derive(Clone,Debug)]
+pub struct QuoteRecord {
+  pub quote_id : i32,
+  pub user_id : String,
+  /// `''` for the global wallet, as stored
+  pub server_id : String,
+  pub crypto_id : String,
+  pub side : OrderSide,
+  pub qty : Numeric,
+  pub price : Numeric,
+  pub expires_at : DateTime<Utc>
+}
+
+/// A price promised by `POST /quotes`. Passing `quote_id` to `/buy` or `/sell` trades at exactly this price
+#[derive(Serialize,Clone,Debug)]
+pub struct Quote {
+  /// Signed, so it can't be guessed or altered
+  #[serde(rename = "quoteId")]
+  pub quote_id : String,
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub side : OrderSide,
+  pub qty : Numeric,
+  /// Price per coin the quote will fill at
+  pub price : Numeric,
+  pub total : Numeric,
+  #[serde(with = "date_formatter", rename = "expiresAt")]
+  pub expires_at : DateTime<Utc>
+}
+
+/// A limit order, resting until the price crosses `limit_price` or it is cancelled / expires
+#[derive(Serialize,Clone,Debug)]
+pub struct Order {
+  #[serde(rename = "orderId")]
+  pub order_id : i32,
+  #[serde(rename = "userId")]
+  pub user_id : String,
+  #[serde(rename = "serverId", skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub side : OrderSide,
+  pub qty : Numeric,
+  #[serde(rename = "limitPrice")]
+  pub limit_price : Numeric,
+  /// Cash held back from the wallet while a buy order is open
+  pub reserved : Numeric,
+  /// One of `open`, `filled`, `cancelled` or `expired`
+  pub status : String,
+  #[serde(with = "date_formatter", rename = "createdAt")]
+  pub created_at : DateTime<Utc>,
+  #[serde(with = "optional_date_formatter", rename = "expiresAt")]
+  pub expires_at : Option<DateTime<Utc>>,
+  #[serde(with = "optional_date_formatter", rename = "closedAt")]
+  pub closed_at : Option<DateTime<Utc>>,
+  #[serde(rename = "fillPrice")]
+  pub fill_price : Option<Numeric>
+}
+
+/// A qty of coin, or the whole position. Accepts a number (or numeric string, for query parameters) or `"all"`.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum PositionQty {
+  All,
+  Qty(Numeric)
+}
+
+impl PositionQty {
+  pub fn qty(&self) -> Option<Numeric> {
+    match self {
+      PositionQty::All => None,
+      PositionQty::Qty(qty) => Some(*qty)
+    }
+  }
+}
+
+impl Serialize for PositionQty {
+  fn serialize<S : serde::Serializer>(&self, serializer : S) -> Result<S::Ok, S::Error> {
+    match self {
+      PositionQty::All => serializer.serialize_str("all"),
+      PositionQty::Qty(qty) => Serialize::serialize(qty, serializer)
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for PositionQty {
+  fn deserialize<D : serde::Deserializer<'de>>(deserializer : D) -> Result<PositionQty, D::Error> {
+    struct PositionQtyVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for PositionQtyVisitor {
+      type Value = PositionQty;
+
+      fn expecting(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a decimal qty or \"all\"")
+      }
+
+      fn visit_str<E : serde::de::Error>(self, v : &str) -> Result<PositionQty, E> {
+        if v.eq_ignore_ascii_case("all") {
+          return Ok(PositionQty::All);
+        }
+        v.parse::<Numeric>().map(PositionQty::Qty).map_err(E::custom)
+      }
+
+      fn visit_f64<E : serde::de::Error>(self, v : f64) -> Result<PositionQty, E> {
+        Numeric::try_from(v).map(PositionQty::Qty).map_err(E::custom)
+      }
+
+      fn visit_i64<E : serde::de::Error>(self, v : i64) -> Result<PositionQty, E> {
+        Ok(PositionQty::Qty(Numeric::from(v)))
+      }
+
+      fn visit_u64<E : serde::de::Error>(self, v : u64) -> Result<PositionQty, E> {
+        Ok(PositionQty::Qty(Numeric::from(v)))
+      }
+    }
+
+    deserializer.deserialize_any(PositionQtyVisitor)
+  }
+}
+
+/// The fees trades from a wallet pay
+#[derive(Serialize,Clone,Debug)]
+pub struct TradeFees {
+  #[serde(rename = "serverId", skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
+  /// Percent of the value of a trade
+  #[serde(rename = "commissionPct")]
+  pub commission_pct : Numeric,
+  /// Smallest commission charged on a trade
+  #[serde(rename = "minFee")]
+  pub min_fee : Numeric,
+  /// Percent between the bid and ask price
+  #[serde(rename = "spreadPct")]
+  pub spread_pct : Numeric,
+  /// The server sets some of its own fees instead of using the defaults
+  pub overridden : bool
+}
+
+/// How much of a coin `/buy` or `/sell` trades
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum TradeSize {
+  Qty(Numeric),
+  /// As much of the coin as this much wallet currency buys, or as much as has to be sold to raise it
+  Amount(Numeric),
+  /// The whole position. Only for selling
+  All
+}
+
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerKind {
+  /// Sells once the price falls to or below the trigger price
+  StopLoss,
+  /// Sells once the price rises to or above the trigger price
+  TakeProfit
+}
+
+impl TriggerKind {
+  /// The value stored in `pricetriggers.kind`
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      TriggerKind::StopLoss => "stop_loss",
+      TriggerKind::TakeProfit => "take_profit"
+    }
+  }
+
+  pub fn from_db(kind : &str) -> TriggerKind {
+    if kind == "take_profit" { TriggerKind::TakeProfit } else { TriggerKind::StopLoss }
+  }
+}
+
+/// A stop-loss or take-profit attached to a position
+#[derive(Serialize,Clone,Debug)]
+pub struct PriceTrigger {
+  #[serde(rename = "triggerId")]
+  pub trigger_id : i32,
+  #[serde(rename = "userId")]
+  pub user_id : String,
+  #[serde(rename = "serverId", skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub kind : TriggerKind,
+  #[serde(rename = "triggerPrice")]
+  pub trigger_price : Numeric,
+  pub qty : PositionQty,
+  /// One of `active`, `fired` or `cancelled`
+  pub status : String,
+  #[serde(with = "date_formatter", rename = "createdAt")]
+  pub created_at : DateTime<Utc>,
+  #[serde(with = "optional_date_formatter", rename = "closedAt")]
+  pub closed_at : Option<DateTime<Utc>>,
+  /// Price that set the trigger off
+  #[serde(rename = "firedPrice")]
+  pub fired_price : Option<Numeric>,
+  /// The sell made when the trigger fired
+  #[serde(rename = "transactionId")]
+  pub transaction_id : Option<i32>,
+  /// Why a trigger was cancelled without firing
+  #[serde(rename = "closeReason")]
+  pub close_reason : Option<String>
+}
+
+/// A row of the `transactions` ledger. Quantities and totals are unsigned, `side` says which way the trade went.
+#[derive(Serialize,Clone,Debug)]
+pub struct Transaction {
+  #[serde(rename = "transactionId")]
+  pub transaction_id : i32,
+  #[serde(with = "date_formatter", rename = "transactionTime")]
+  pub transaction_time : DateTime<Utc>,
+  #[serde(rename = "userId")]
+  pub user_id : String,
+  #[serde(rename = "serverId", skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub side : OrderSide,
+  pub qty : Numeric,
+  /// Price per coin the trade filled at
+  pub price : Numeric,
+  /// Cash paid for a buy, or received for a sell, after the fee
+  pub total : Numeric,
+  /// Commission charged on the trade
+  pub fee : Numeric
+}
+
+/// One page of a user's transaction history
+#[derive(Serialize,Clone,Debug)]
+pub struct TransactionPage {
+  pub transactions : Vec<Transaction>,
+  /// Pass back as `cursor` to get the next (older) page, missing on the last page
+  #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+  pub next_cursor : Option<i32>
+}
+
+/// The look-back windows documented in api.md
+#[derive(Deserialize,Clone,Copy,Debug,PartialEq,Default)]
+pub enum DateRange {
+  #[default]
+  #[serde(rename = "day")]
+  Day,
+  #[serde(rename = "week")]
+  Week,
+  #[serde(rename = "month")]
+  Month,
+  #[serde(rename = "90days")]
+  NinetyDays,
+  #[serde(rename = "6months")]
+  SixMonths
+}
+
+impl DateRange {
+  pub fn duration(&self) -> chrono::Duration {
+    match self {
+      DateRange::Day => chrono::Duration::days(1),
+      DateRange::Week => chrono::Duration::weeks(1),
+      DateRange::Month => chrono::Duration::days(30),
+      DateRange::NinetyDays => chrono::Duration::days(90),
+      DateRange::SixMonths => chrono::Duration::days(182)
+    }
+  }
+
+  /// A bucket size that gives a few hundred points over the range
+  pub fn default_interval(&self) -> Interval {
+    match self {
+      DateRange::Day => Interval(5 * 60),
+      DateRange::Week => Interval(60 * 60),
+      DateRange::Month => Interval(4 * 60 * 60),
+      DateRange::NinetyDays | DateRange::SixMonths => Interval(24 * 60 * 60)
+    }
+  }
+}
+
+/// A bucket width for downsampling a time series, written like `5m`, `1h`, `1d` or `1w`. Held as seconds.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Interval(pub i64);
+
+impl Interval {
+  pub fn seconds(&self) -> i64 {
+    self.0
+  }
+}
+
+impl std::str::FromStr for Interval {
+  type Err = String;
+
+  fn from_str(s : &str) -> Result<Interval, String> {
+    let invalid = || format!("Invalid interval `{}`, expected a number followed by m, h, d or w (e.g. 5m, 1h)", s);
+    let (count, unit) = match s.char_indices().last() {
+      Some((i, _)) if i > 0 => s.split_at(i),
+      _ => return Err(invalid())
+    };
+    let count = count.parse::<i64>().map_err(|_| invalid())?;
+    let unit_secs = match unit {
+      "m" => 60,
+      "h" => 60 * 60,
+      "d" => 24 * 60 * 60,
+      "w" => 7 * 24 * 60 * 60,
+      _ => return Err(invalid())
+    };
+    if count <= 0 {
+      return Err(invalid());
+    }
+    count.checked_mul(unit_secs).map(Interval).ok_or_else(invalid)
+  }
+}
+
+impl<'de> Deserialize<'de> for Interval {
+  fn deserialize<D : serde::Deserializer<'de>>(deserializer : D) -> Result<Interval, D::Error> {
+    String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// One sample of a coin's price series
+#[derive(Serialize,Clone,Debug)]
+pub struct PricePoint {
+  #[serde(with = "date_formatter", rename = "asOf")]
+  pub as_of : DateTime<Utc>,
+  pub price : Numeric,
+  #[serde(rename = "marketCap")]
+  pub market_cap : Numeric,
+  pub volume : Numeric
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct PriceHistory {
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub symbol : String,
+  pub name : String,
+  #[serde(with = "date_formatter")]
+  pub from : DateTime<Utc>,
+  #[serde(with = "date_formatter")]
+  pub to : DateTime<Utc>,
+  /// Seconds per point. Each point is the last snapshot taken in its bucket
+  pub resolution : i64,
+  pub points : Vec<PricePoint>
+}
+
+/// A wallet's cash plus the value of its holdings at one point in time
+#[derive(Serialize,Clone,Debug)]
+pub struct NetWorthPoint {
+  #[serde(with = "date_formatter", rename = "asOf")]
+  pub as_of : DateTime<Utc>,
+  #[serde(rename = "netWorth")]
+  pub net_worth : Numeric
+}
+
+/// A wallet as it stood when the snapshot job last ran in an interval
+#[derive(Serialize,Clone,Debug)]
+pub struct NetWorthSnapshot {
+  /// Start of the snapshot interval
+  #[serde(with = "date_formatter", rename = "asOf")]
+  pub as_of : DateTime<Utc>,
+  /// The wallet balance, plus what open buy orders reserved
+  pub cash : Numeric,
+  #[serde(rename = "portfolioValue")]
+  pub portfolio_value : Numeric,
+  #[serde(rename = "netWorth")]
+  pub net_worth : Numeric
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct NetWorthHistory {
+  #[serde(rename = "userId")]
+  pub user_id : String,
+  #[serde(rename = "serverId", skip_serializing_if = "Option::is_none")]
+  pub server_id : Option<String>,
+  #[serde(with = "date_formatter")]
+  pub from : DateTime<Utc>,
+  #[serde(with = "date_formatter")]
+  pub to : DateTime<Utc>,
+  /// Net worth of the last snapshot minus the first, missing when there are no snapshots
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub change : Option<Numeric>,
+  /// `change` as a percentage of the first snapshot
+  #[serde(rename = "changePct", skip_serializing_if = "Option::is_none")]
+  pub change_pct : Option<Numeric>,
+  pub snapshots : Vec<NetWorthSnapshot>
+}
+
+impl NetWorthHistory {
+  pub fn new(user_id : String, server_id : Option<String>, from : DateTime<Utc>, to : DateTime<Utc>, snapshots : Vec<NetWorthSnapshot>) -> NetWorthHistory {
+    let change = match (snapshots.first(), snapshots.last()) {
+      (Some(first), Some(last)) => Some((last.net_worth - first.net_worth, first.net_worth)),
+      _ => None
+    };
+    NetWorthHistory {
+      user_id,
+      server_id,
+      from,
+      to,
+      change : change.map(|(change, _)| change),
+      change_pct : change.map(|(change, start)| pnl_pct(change, start)),
+      snapshots
+    }
+  }
+}
+
+/// Open/high/low/close of a coin's price over one bucket
+#[derive(Serialize,Clone,Debug)]
+pub struct Candle {
+  /// Start of the bucket
+  #[serde(with = "date_formatter", rename = "openTime")]
+  pub open_time : DateTime<Utc>,
+  pub open : Numeric,
+  pub high : Numeric,
+  pub low : Numeric,
+  pub close : Numeric,
+  /// 24h trading volume as of the last snapshot in the bucket
+  pub volume : Numeric,
+  /// Number of snapshots the bucket was built from
+  pub samples : i64
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct CandleSeries {
+  #[serde(rename = "cryptoId")]
+  pub crypto_id : String,
+  pub symbol : String,
+  pub name : String,
+  /// Seconds per candle
+  pub interval : i64,
+  pub candles : Vec<Candle>
+}
+
+/// What an API key may do. Each route requires one, and `Admin` keys pass all of them
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq,Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+  /// Prices, balances, portfolios and other lookups
+  Read,
+  /// Buying, selling, quotes, orders, triggers and daily rewards
+  Trade,
+  /// Changing fees, and listing, creating, rotating, revoking, limiting and signing API keys. Also passes every other scope
+  Admin,
+  /// Setting who is on a server's leaderboard
+  LeaderboardWrite
+}
+
+impl Scope {
+  /// The value stored in `apikeys.scopes`
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Scope::Read => "read",
+      Scope::Trade => "trade",
+      Scope::Admin => "admin",
+      Scope::LeaderboardWrite => "leaderboard-write"
+    }
+  }
+
+  pub fn from_db(scope : &str) -> Option<Scope> {
+    match scope {
+      "read" => Some(Scope::Read),
+      "trade" => Some(Scope::Trade),
+      "admin" => Some(Scope::Admin),
+      "leaderboard-write" => Some(Scope::LeaderboardWrite),
+      _ => None
+    }
+  }
+}
+
+/// How API keys are checked, set with `CB_AUTH_MODE`
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMode {
+  /// Requests without a valid key, or without the scope a route needs, are refused
+  Enforce,
+  /// Keys aren't needed. A valid key still only gets its own scopes
+  Disabled,
+  /// Every request is let through, but the ones `Enforce` would refuse are logged
+  LogOnly
+}
+
+impl AuthMode {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      AuthMode::Enforce => "enforce",
+      AuthMode::Disabled => "disabled",
+      AuthMode::LogOnly => "log-only"
+    }
+  }
+}
+
+impl std::str::FromStr for AuthMode {
+  type Err = String;
+
+  fn from_str(s : &str) -> Result<AuthMode, String> {
+    match s {
+      "enforce" => Ok(AuthMode::Enforce),
+      "disabled" => Ok(AuthMode::Disabled),
+      "log-only" => Ok(AuthMode::LogOnly),
+      _ => Err(format!("Invalid auth mode `{}`, expected enforce, disabled or log-only", s))
+    }
+  }
+}
+
+/// A row of the `apikeys` table
+#[derive(Clone,Debug)]
+pub struct ApiKeyRecord {
+  pub id : i32,
+  pub salt : Vec<u8>,
+  /// `hash_api_key(salt, secret)`
+  pub key_hash : Vec<u8>,
+  pub scopes : Vec<Scope>,
+  /// When a rotated key stops working
+  pub expires_at : Option<DateTime<Utc>>,
+  /// Overrides `CB_RATE_LIMIT_KEY_READ`
+  pub read_limit : Option<u32>,
+  /// Overrides `CB_RATE_LIMIT_KEY_TRADE`
+  pub trade_limit : Option<u32>,
+  /// Requests made with the key have to be signed with this, see `signing`
+  pub signing_secret : Option<String>,
+  /// A key from before `<id>.<secret>`, sent as the whole secret, that `key_hash` hashes
+  pub legacy : bool
+}
+
+/// An API key as listed by `GET /apikeys`. The secret is never included
+#[derive(Serialize,Clone,Debug)]
+pub struct ApiKeyInfo {
+  #[serde(rename = "keyId")]
+  pub key_id : i32,
+  pub description : Option<String>,
+  pub scopes : Vec<Scope>,
+  /// Requests allowed per rate limit window, `None` for the defaults
+  #[serde(rename = "readLimit")]
+  pub read_limit : Option<u32>,
+  #[serde(rename = "tradeLimit")]
+  pub trade_limit : Option<u32>,
+  /// Requests made with the key have to be signed
+  pub signed : bool,
+  /// The key predates `<id>.<secret>` keys and is still sent in its old form, until it is rotated
+  pub legacy : bool,
+  /// One of `active`, `expiring` (rotated, but still in its grace period), `expired` or `revoked`
+  pub status : String,
+  #[serde(with = "date_formatter", rename = "createdAt")]
+  pub created_at : DateTime<Utc>,
+  #[serde(with = "optional_date_formatter", rename = "lastUsedAt")]
+  pub last_used_at : Option<DateTime<Utc>>,
+  #[serde(with = "optional_date_formatter", rename = "expiresAt")]
+  pub expires_at : Option<DateTime<Utc>>,
+  #[serde(with = "optional_date_formatter", rename = "revokedAt")]
+  pub revoked_at : Option<DateTime<Utc>>
+}
+
+/// A key made by `POST /apikeys` or a rotation. This is the only time `key` is shown
+#[derive(Serialize,Clone,Debug)]
+pub struct CreatedApiKey {
+  /// `<keyId>.<secret>`, sent in the `X-CB-API-KEY` header
+  pub key : String,
+  /// What signed keys sign their requests with. Only shown here and by `PUT /apikeys/{keyId}/signing`
+  #[serde(rename = "signingSecret", skip_serializing_if = "Option::is_none")]
+  pub signing_secret : Option<String>,
+  #[serde(flatten)]
+  pub info : ApiKeyInfo
+}
+
+/// A key after `PUT /apikeys/{keyId}/signing`, with its new signing secret if it is now signed
+#[derive(Serialize,Clone,Debug)]
+pub struct ApiKeySigning {
+  #[serde(rename = "signingSecret", skip_serializing_if = "Option::is_none")]
+  pub signing_secret : Option<String>,
+  #[serde(flatten)]
+  pub info : ApiKeyInfo
+}
+
+#[derive(Serialize,Clone,Debug)]
+pub struct RotatedApiKey {
+  #[serde(flatten)]
+  pub key : CreatedApiKey,
+  /// The key it replaces, which works until its `expiresAt`
+  pub replaces : ApiKeyInfo
 }
 
 pub struct RootAppState {
-    pub broker_mapper : BrokerMapper
+    pub broker_mapper : BrokerMapper,
+    pub config : Config,
+    pub quote_signer : QuoteSigner
 }
 
 // Copied from serde example https://serde.rs/custom-date-format.html
@@ -71,7 +792,7 @@ mod date_formatter {
     use chrono::{DateTime, Utc, TimeZone};
     use serde::{self, Deserialize, Serializer, Deserializer};
 
-    const FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
     // The signature of a serialize_with function must follow the pattern:
     //
@@ -106,6 +827,60 @@ mod date_formatter {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Utc.datetime_from_str(&s, FORMAT).map_err(serde::de::Error::custom)
+        parse(&s).map_err(serde::de::Error::custom)
+    }
+
+    pub fn parse(s : &str) -> chrono::ParseResult<DateTime<Utc>> {
+        Utc.datetime_from_str(s, FORMAT)
+    }
+}
+
+/// Same format as `date_formatter`, for timestamps that may not be set yet. Serialized as `null` when missing.
+pub mod optional_date_formatter {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serializer, Deserializer};
+
+    pub fn serialize<S>(
+        date: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => super::date_formatter::serialize(date, serializer),
+            None => serializer.serialize_none()
+        }
+    }
+
+    // Used on optional query parameters, which are just missing rather than null, so pair this with `#[serde(default)]`.
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => super::date_formatter::parse(&s).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_intervals() {
+    assert_eq!("5m".parse::<Interval>(), Ok(Interval(300)));
+    assert_eq!("2w".parse::<Interval>(), Ok(Interval(2 * 7 * 24 * 60 * 60)));
+  }
+
+  #[test]
+  fn refuses_bad_intervals() {
+    for s in ["", "m", "0h", "-1d", "5", "5x", "5é", "é", "99999999999999999w"] {
+      assert!(s.parse::<Interval>().is_err(), "{} parsed", s);
     }
-}
\ No newline at end of file
+  }
+}
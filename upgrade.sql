@@ -0,0 +1,60 @@
+-- Upgrades a database made with the released schema.sql (cryptodata, wallet, apikeys, serverpatrons and transactions,
+-- with one wallet per user and API keys stored as key_str) to the current one. Run it with psql from this directory, as it loads schema.sql:
+--   psql -v ON_ERROR_STOP=1 -d <database> -f upgrade.sql
+-- The old tables are moved out of the way, schema.sql makes the new ones, and the rows are copied across into the
+-- global wallet (serverId ''). It all happens in one transaction, so a failed upgrade leaves the database as it was.
+
+BEGIN;
+
+-- views and functions over the old tables. buy_currency and sell_currency gained parameters and a return value, and
+-- CREATE can't replace those, so every overload goes
+DROP VIEW IF EXISTS vNetworth;
+DROP VIEW IF EXISTS vPortfolio;
+DO $BODY$
+declare fn regprocedure;
+BEGIN
+for fn in select oid::regprocedure from pg_proc where proname in ('buy_currency', 'sell_currency') loop
+  execute 'DROP FUNCTION ' || fn;
+end loop;
+end $BODY$;
+
+-- park the old tables, freeing the index and constraint names schema.sql reuses
+ALTER TABLE cryptodata RENAME TO old_cryptodata;
+ALTER TABLE old_cryptodata RENAME CONSTRAINT PK_cryptodata TO PK_old_cryptodata;
+DROP INDEX IDX_cryptoname;
+DROP INDEX IDX_cryptoasof;
+ALTER TABLE wallet RENAME TO old_wallet;
+ALTER TABLE apikeys RENAME TO old_apikeys;
+ALTER TABLE serverpatrons RENAME TO old_serverpatrons;
+DROP INDEX IDX_serverpatrons_serverId;
+ALTER TABLE transactions RENAME TO old_transactions;
+DROP INDEX IDX_transactions;
+
+\ir schema.sql
+
+INSERT INTO cryptodata (id, asOf, symbol, name, price, image_url, market_cap, volume, coingecko_timestamp)
+SELECT id, asOf, symbol, name, price, image_url, market_cap, volume, coingecko_timestamp FROM old_cryptodata;
+
+INSERT INTO wallet (userId, serverId, walletBalance)
+SELECT userId, '', walletBalance FROM old_wallet;
+
+INSERT INTO serverpatrons (serverId, userId, ts)
+SELECT serverId, userId, ts FROM old_serverpatrons;
+
+-- the fill price wasn't recorded before, and cost is rounded, so it is left NULL rather than guessed
+INSERT INTO transactions (transactionId, transactionTime, userId, serverId, cryptoId, cost, buySellIndicator, qty)
+SELECT transactionId, transactionTime, userId, '', cryptoId, cost, buySellIndicator, qty FROM old_transactions;
+SELECT setval(pg_get_serial_sequence('transactions', 'transactionid'), COALESCE(MAX(transactionId), 0) + 1, false) FROM transactions;
+
+-- keys were stored and sent as key_str. They keep their ids and are hashed like any other secret, but as legacy keys they
+-- are still sent whole until rotate_api_key replaces them. They could call every endpoint there was, so every scope but admin
+INSERT INTO apikeys (id, salt, keyHash, scopes, description, legacy)
+SELECT id, salt, hash_api_key(salt, key_str), '{read,trade,leaderboard-write}', description, true
+FROM (SELECT id, key_str, description, decode(replace(gen_random_uuid()::text, '-', ''), 'hex') AS salt FROM old_apikeys WHERE key_str IS NOT NULL) k;
+SELECT setval(pg_get_serial_sequence('apikeys', 'id'), COALESCE(MAX(id), 0) + 1, false) FROM apikeys;
+INSERT INTO apikeyaudit (keyId, action, detail)
+SELECT id, 'created', 'legacy key carried over by upgrade.sql' FROM apikeys;
+
+DROP TABLE old_cryptodata, old_wallet, old_apikeys, old_serverpatrons, old_transactions;
+
+COMMIT;